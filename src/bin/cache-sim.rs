@@ -0,0 +1,269 @@
+//! Trace-driven cache simulator, which replays traces through the eviction policies of
+//! `surrealkv::storage::cache` and prints their miss-ratio curves.
+//!
+//! Usage: cache-sim [OPTIONS] TRACE...
+//!
+//! Options:
+//!   --format lis|csv|oracleGeneral  Format of the traces, guessed from their names by default.
+//!   --policies NAME,...             Policies to simulate, all of them by default.
+//!   --sizes SIZE,...                Cache sizes in entries, or in percent of the unique keys of
+//!                                   each trace with a `%` suffix. Defaults to 5%,10%,...,100%.
+//!   --output csv|json               Output format, csv by default.
+use std::env;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use surrealkv::storage::cache::s3fifo::S3FifoConfig;
+use surrealkv::storage::cache::trace::{self, TraceFormat};
+use surrealkv::storage::cache::{arc, clock, lru, s3fifo, sieve, Policy};
+
+const POLICIES: &[&str] = &["s3fifo", "s3fifo-tinylfu", "lru", "clock", "sieve", "arc"];
+
+const USAGE: &str = "usage: cache-sim [--format lis|csv|oracleGeneral] [--policies NAME,...] \
+                     [--sizes SIZE[%],...] [--output csv|json] TRACE...";
+
+/// Size of a simulated cache.
+#[derive(Clone, Copy)]
+enum Size {
+    Entries(usize),
+    Percent(f64),
+}
+
+impl Size {
+    fn entries(self, unique_keys: usize) -> usize {
+        match self {
+            Size::Entries(entries) => entries,
+            Size::Percent(percent) => (unique_keys as f64 * percent / 100.0).round() as usize,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Csv,
+    Json,
+}
+
+struct Args {
+    format: Option<TraceFormat>,
+    policies: Vec<String>,
+    sizes: Vec<Size>,
+    output: Output,
+    traces: Vec<PathBuf>,
+}
+
+/// A point of a miss-ratio curve.
+struct Point<'a> {
+    trace: &'a Path,
+    policy: &'a str,
+    cache_size: usize,
+    requests: usize,
+    unique_keys: usize,
+    misses: u64,
+}
+
+impl Point<'_> {
+    fn miss_ratio(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.misses as f64 / self.requests as f64
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{message}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        format: None,
+        policies: POLICIES.iter().map(|policy| policy.to_string()).collect(),
+        sizes: (1..=20).map(|i| Size::Percent(i as f64 * 5.0)).collect(),
+        output: Output::Csv,
+        traces: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--format" => parsed.format = Some(value()?.parse()?),
+            "--policies" => {
+                parsed.policies = value()?.split(',').map(str::to_string).collect();
+                if let Some(policy) = parsed
+                    .policies
+                    .iter()
+                    .find(|p| !POLICIES.contains(&p.as_str()))
+                {
+                    return Err(format!("unknown policy: {policy}"));
+                }
+            }
+            "--sizes" => {
+                parsed.sizes = value()?
+                    .split(',')
+                    .map(parse_size)
+                    .collect::<Result<_, _>>()?
+            }
+            "--output" => {
+                parsed.output = match value()?.as_str() {
+                    "csv" => Output::Csv,
+                    "json" => Output::Json,
+                    output => return Err(format!("unknown output format: {output}")),
+                }
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {arg}")),
+            _ => parsed.traces.push(PathBuf::from(arg)),
+        }
+    }
+    if parsed.traces.is_empty() {
+        return Err("no trace given".to_string());
+    }
+    Ok(parsed)
+}
+
+fn parse_size(size: &str) -> Result<Size, String> {
+    let invalid = || format!("invalid cache size: {size}");
+    match size.strip_suffix('%') {
+        Some(percent) => percent.parse().map(Size::Percent).map_err(|_| invalid()),
+        None => size.parse().map(Size::Entries).map_err(|_| invalid()),
+    }
+}
+
+fn new_policy(name: &str, capacity: NonZeroUsize) -> Box<dyn Policy<u64, ()>> {
+    match name {
+        "s3fifo" => Box::new(s3fifo::Cache::new(capacity)),
+        "s3fifo-tinylfu" => Box::new(s3fifo::Cache::with_config(
+            capacity,
+            S3FifoConfig::new().with_admission_filter(true),
+        )),
+        "lru" => Box::new(lru::Cache::new(capacity)),
+        "clock" => Box::new(clock::Cache::new(capacity)),
+        "sieve" => Box::new(sieve::Cache::new(capacity)),
+        "arc" => Box::new(arc::Cache::new(capacity)),
+        _ => unreachable!("policies are checked when parsing arguments"),
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let formats = args
+        .traces
+        .iter()
+        .map(|path| {
+            args.format
+                .or_else(|| TraceFormat::from_path(path))
+                .ok_or(format!(
+                    "cannot guess the format of {}, use --format",
+                    path.display()
+                ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut out = io::stdout().lock();
+    let mut first = true;
+    if args.output == Output::Csv {
+        writeln!(
+            out,
+            "trace,policy,cache_size,requests,unique_keys,misses,miss_ratio"
+        )
+        .map_err(|e| e.to_string())?;
+    } else {
+        write!(out, "[").map_err(|e| e.to_string())?;
+    }
+
+    for (path, format) in args.traces.iter().zip(formats) {
+        let requests =
+            trace::read_trace(path, format).map_err(|e| format!("{}: {e}", path.display()))?;
+        let unique_keys = trace::unique_keys(&requests);
+        eprintln!(
+            "{}: {} requests, {unique_keys} unique keys",
+            path.display(),
+            requests.len()
+        );
+
+        for size in &args.sizes {
+            let Some(capacity) = NonZeroUsize::new(size.entries(unique_keys)) else {
+                continue;
+            };
+            for policy in &args.policies {
+                let misses = trace::replay(new_policy(policy, capacity).as_mut(), &requests);
+                let point = Point {
+                    trace: path,
+                    policy,
+                    cache_size: capacity.get(),
+                    requests: requests.len(),
+                    unique_keys,
+                    misses,
+                };
+                match args.output {
+                    Output::Csv => write_csv(&mut out, &point),
+                    Output::Json => write_json(&mut out, &point, first),
+                }
+                .map_err(|e| e.to_string())?;
+                first = false;
+            }
+        }
+    }
+
+    if args.output == Output::Json {
+        writeln!(out, "\n]").map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, point: &Point) -> io::Result<()> {
+    let trace = point.trace.display().to_string();
+    let trace = if trace.contains([',', '"']) {
+        format!("\"{}\"", trace.replace('"', "\"\""))
+    } else {
+        trace
+    };
+    writeln!(
+        out,
+        "{trace},{},{},{},{},{},{}",
+        point.policy,
+        point.cache_size,
+        point.requests,
+        point.unique_keys,
+        point.misses,
+        point.miss_ratio()
+    )
+}
+
+fn write_json(out: &mut impl Write, point: &Point, first: bool) -> io::Result<()> {
+    let trace = point.trace.display().to_string();
+    let mut escaped = String::with_capacity(trace.len());
+    for c in trace.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    write!(
+        out,
+        "{}\n  {{\"trace\": \"{escaped}\", \"policy\": \"{}\", \"cache_size\": {}, \
+         \"requests\": {}, \"unique_keys\": {}, \"misses\": {}, \"miss_ratio\": {}}}",
+        if first { "" } else { "," },
+        point.policy,
+        point.cache_size,
+        point.requests,
+        point.unique_keys,
+        point.misses,
+        point.miss_ratio()
+    )
+}
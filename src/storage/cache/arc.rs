@@ -0,0 +1,182 @@
+/// This is an implementation of ARC from "ARC: A Self-Tuning, Low Overhead Replacement Cache" by
+/// Nimrod Megiddo and Dharmendra S. Modha. It is used as a point of comparison for S3-FIFO.
+use lru::LruCache;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+
+use crate::storage::cache::{s3fifo::CacheStats, Policy};
+
+/// Cache is an implementation of ARC holding up to a fixed number of entries.
+///
+/// Entries seen once live in the LRU list `t1`, and entries seen at least twice in the LRU list
+/// `t2`. The keys evicted from each list are remembered in the ghost lists `b1` and `b2`. The
+/// target size `p` of `t1` adapts to the workload: a miss on a key of `b1` means that `t1` was too
+/// small, and grows it, while a miss on a key of `b2` shrinks it. Together, the four lists remember
+/// at most twice as many keys as the cache holds.
+pub struct Cache<K, V> {
+    t1: LruCache<K, V>,
+    t2: LruCache<K, V>,
+    b1: LruCache<K, ()>,
+    b2: LruCache<K, ()>,
+    /// Target size of `t1`.
+    p: usize,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Creates a new cache with the given maximum number of entries.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            t1: LruCache::unbounded(),
+            t2: LruCache::unbounded(),
+            b1: LruCache::unbounded(),
+            b2: LruCache::unbounded(),
+            p: 0,
+            capacity: capacity.get(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Evicts the least recently used entry of `t1` into `b1` if `t1` is over its target size, and
+    /// the least recently used entry of `t2` into `b2` otherwise. Does nothing while the cache is
+    /// not full.
+    fn replace(&mut self, in_b2: bool) {
+        if self.t1.len() + self.t2.len() < self.capacity {
+            return;
+        }
+        let t1_over_target = self.t1.len() > self.p || (in_b2 && self.t1.len() == self.p);
+        if !self.t1.is_empty() && (t1_over_target || self.t2.is_empty()) {
+            if let Some((key, _)) = self.t1.pop_lru() {
+                self.b1.put(key, ());
+            }
+        } else if let Some((key, _)) = self.t2.pop_lru() {
+            self.b2.put(key, ());
+        }
+    }
+}
+
+impl<K, V> Policy<K, V> for Cache<K, V>
+where
+    K: Hash + Eq,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if let Some((key, value)) = self.t1.pop_entry(key) {
+            self.t2.put(key, value);
+        }
+        let value = self.t2.get(key);
+        match value {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        value
+    }
+
+    fn insert(&mut self, key: K, value: V) -> bool {
+        if self.t1.contains(&key) || self.t2.contains(&key) {
+            return false;
+        }
+        self.stats.insertions += 1;
+
+        if self.b1.contains(&key) {
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(self.capacity);
+            self.replace(false);
+            self.b1.pop(&key);
+            self.t2.put(key, value);
+            return true;
+        }
+        if self.b2.contains(&key) {
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.replace(true);
+            self.b2.pop(&key);
+            self.t2.put(key, value);
+            return true;
+        }
+
+        let l1 = self.t1.len() + self.b1.len();
+        if l1 >= self.capacity {
+            if self.t1.len() < self.capacity {
+                self.b1.pop_lru();
+                self.replace(false);
+            } else {
+                self.t1.pop_lru();
+            }
+        } else {
+            let total = l1 + self.t2.len() + self.b2.len();
+            if total >= 2 * self.capacity {
+                self.b2.pop_lru();
+            }
+            self.replace(false);
+        }
+        self.t1.put(key, value);
+        true
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.t1.pop(key).or_else(|| self.t2.pop(key))
+    }
+
+    fn len(&self) -> usize {
+        self.t1.len() + self.t2.len()
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frequent_entries_survive_a_scan() {
+        let mut cache = Cache::new(NonZeroUsize::new(4).unwrap());
+        for i in 0..2 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        for i in 100..110 {
+            cache.insert(i, i);
+            assert!(cache.len() <= 4);
+        }
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn test_ghost_hit_adapts_target() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(0, 0);
+        cache.get(&0);
+        cache.insert(1, 1);
+        // 1 is evicted from t1 into b1.
+        cache.insert(2, 2);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.b1.contains(&1));
+
+        // Coming back from b1 grows the target size of t1 and lands in t2.
+        assert!(cache.insert(1, 1));
+        assert_eq!(cache.p, 1);
+        assert!(cache.t2.contains(&1));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        cache.get(&1);
+        assert!(!cache.insert(1, 10));
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.remove(&1), Some(1));
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().insertions, 2);
+    }
+}
@@ -0,0 +1,159 @@
+/// This is an implementation of the CLOCK eviction policy, a one-bit approximation of LRU. It is
+/// used as a point of comparison for S3-FIFO.
+use hashbrown::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+
+use crate::storage::cache::{s3fifo::CacheStats, Policy};
+
+/// An entry of the clock, with the bit set by accesses since the hand last passed it.
+struct Slot<K, V> {
+    key: K,
+    value: V,
+    referenced: bool,
+}
+
+/// Cache is an implementation of CLOCK holding up to a fixed number of entries.
+///
+/// Entries are arranged in a circular buffer swept by a hand. A hit sets the reference bit of its
+/// entry. To make room, the hand clears the bit of every referenced entry it passes, and evicts
+/// the first entry whose bit is already clear, whose slot the new entry takes.
+pub struct Cache<K, V> {
+    slots: Vec<Option<Slot<K, V>>>,
+    /// Slots emptied by removals.
+    free: Vec<usize>,
+    /// Index from the keys of the entries to their slots.
+    index: HashMap<K, usize>,
+    /// Slot the hand points at.
+    hand: usize,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates a new cache with the given maximum number of entries.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            hand: 0,
+            capacity: capacity.get(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Moves the hand until it finds an entry which was not referenced since its last pass, and
+    /// evicts it. Returns the emptied slot. Must only be called when every slot is taken.
+    fn evict(&mut self) -> usize {
+        loop {
+            let hand = self.hand;
+            self.hand = (hand + 1) % self.slots.len();
+            let slot = self.slots[hand]
+                .as_mut()
+                .expect("a full clock has no empty slot");
+            if slot.referenced {
+                slot.referenced = false;
+                continue;
+            }
+            let slot = self.slots[hand].take().unwrap();
+            self.index.remove(&slot.key);
+            return hand;
+        }
+    }
+}
+
+impl<K, V> Policy<K, V> for Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let Some(&slot) = self.index.get(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        let slot = self.slots[slot].as_mut().unwrap();
+        slot.referenced = true;
+        Some(&slot.value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> bool {
+        if self.index.contains_key(&key) {
+            return false;
+        }
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None if self.slots.len() < self.capacity => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+            None => self.evict(),
+        };
+        self.index.insert(key.clone(), slot);
+        self.slots[slot] = Some(Slot {
+            key,
+            value,
+            referenced: false,
+        });
+        self.stats.insertions += 1;
+        true
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let slot = self.index.remove(key)?;
+        self.free.push(slot);
+        self.slots[slot].take().map(|slot| slot.value)
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referenced_entries_get_a_second_chance() {
+        let mut cache = Cache::new(NonZeroUsize::new(3).unwrap());
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.get(&0);
+
+        // The hand clears the bit of 0 and evicts 1, then evicts 2 on the next insertion.
+        cache.insert(3, 3);
+        assert_eq!(cache.get(&1), None);
+        cache.insert(4, 4);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_remove_frees_a_slot() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        assert!(!cache.insert(1, 10));
+
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.remove(&0), None);
+        cache.insert(2, 2);
+        assert_eq!(cache.get(&1), Some(&1));
+        assert_eq!(cache.get(&2), Some(&2));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.insertions, 3);
+    }
+}
@@ -0,0 +1,361 @@
+/// On-disk second tier for an in-memory cache, keeping the values it evicts in a local file so
+/// that they can be read back without going to slower storage.
+use bytes::Bytes;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Snapshot of the statistics of a disk cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskCacheStats {
+    /// Number of lookups which found a value.
+    pub hits: u64,
+    /// Number of lookups which found no value, including values which failed their checksum.
+    pub misses: u64,
+    /// Number of values written to the file.
+    pub insertions: u64,
+    /// Number of values overwritten by newer ones.
+    pub evictions: u64,
+    /// Number of values which failed to be written to the file.
+    pub write_errors: u64,
+    /// Number of values currently in the cache.
+    pub len: u64,
+    /// Total length of the values currently in the cache.
+    pub weight: u64,
+}
+
+/// Location of a value in the file.
+struct Record {
+    /// Logical position of the value, which grows with every write. The file offset is the
+    /// position modulo the capacity.
+    position: u64,
+    len: u64,
+    crc: u32,
+    /// False while the value is being written, during which lookups miss.
+    written: bool,
+}
+
+/// Bookkeeping of the cache. The file is read and written outside of the lock, at the offsets
+/// reserved here.
+struct Inner<K> {
+    index: HashMap<K, Record>,
+    /// Keys in the order their values were written, with their position.
+    queue: VecDeque<(K, u64)>,
+    /// Logical position of the next write.
+    head: u64,
+    weight: u64,
+}
+
+/// DiskCache is a log-structured cache of values in a single file of fixed size.
+///
+/// The file is used as a ring buffer: values are appended at the head, wrapping around to the
+/// start of the file when a value does not fit before its end, and overwrite the oldest values in
+/// FIFO order. The index lives in memory, so the file is truncated when the cache is opened.
+/// Every value is checked against a checksum when read back, and a mismatch counts as a miss, which
+/// also covers reads racing with a write overwriting the value.
+pub struct DiskCache<K> {
+    file: File,
+    inner: Mutex<Inner<K>>,
+    capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+    write_errors: AtomicU64,
+}
+
+impl<K> DiskCache<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Opens the cache in the file at the given path, holding values up to `capacity` bytes in
+    /// total. Any previous content of the file is discarded.
+    pub fn open(path: &Path, capacity: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            inner: Mutex::new(Inner {
+                index: HashMap::new(),
+                queue: VecDeque::new(),
+                head: 0,
+                weight: 0,
+            }),
+            capacity: capacity.max(1),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            insertions: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the value of the given key if it is in the cache and passes its checksum.
+    pub fn get(&self, key: &K) -> Option<Bytes> {
+        let (position, len, crc) = match self.inner.lock().index.get(key) {
+            Some(record) if record.written => (record.position, record.len, record.crc),
+            _ => {
+                self.misses.fetch_add(1, Relaxed);
+                return None;
+            }
+        };
+
+        let mut value = vec![0; len as usize];
+        let read = read_at(&self.file, &mut value, position % self.capacity);
+        if read.is_ok() && crc32fast::hash(&value) == crc {
+            self.hits.fetch_add(1, Relaxed);
+            return Some(Bytes::from(value));
+        }
+
+        // The value is unreadable or was overwritten meanwhile, drop it so that it is fetched
+        // again, unless the key was written anew since.
+        let mut inner = self.inner.lock();
+        if inner.index.get(key).map(|record| record.position) == Some(position) {
+            inner.index.remove(key);
+            inner.weight -= len;
+        }
+        self.misses.fetch_add(1, Relaxed);
+        None
+    }
+
+    /// Writes the value of the given key at the head of the file, overwriting the oldest values as
+    /// needed. Returns false if the key is already present, the value is larger than the cache, or
+    /// concurrent writes overwrote the value before it was fully written.
+    pub fn insert(&self, key: K, value: &[u8]) -> io::Result<bool> {
+        let len = value.len() as u64;
+        let crc = crc32fast::hash(value);
+        let mut inner = self.inner.lock();
+        if len > self.capacity || inner.index.contains_key(&key) {
+            return Ok(false);
+        }
+
+        // Wrap around rather than splitting the value at the end of the file.
+        let mut position = inner.head;
+        if position % self.capacity + len > self.capacity {
+            position = position.next_multiple_of(self.capacity);
+        }
+        let end = position + len;
+        while let Some((_, start)) = inner.queue.front() {
+            if *start + self.capacity >= end {
+                break;
+            }
+            let (key, start) = inner.queue.pop_front().unwrap();
+            if inner.index.get(&key).map(|record| record.position) == Some(start) {
+                let record = inner.index.remove(&key).unwrap();
+                inner.weight -= record.len;
+                self.evictions.fetch_add(1, Relaxed);
+            }
+        }
+
+        // Reserve the range before releasing the lock, so that concurrent writes go elsewhere.
+        inner.head = end;
+        inner.weight += len;
+        inner.queue.push_back((key.clone(), position));
+        inner.index.insert(
+            key.clone(),
+            Record {
+                position,
+                len,
+                crc,
+                written: false,
+            },
+        );
+        drop(inner);
+
+        let written = write_at(&self.file, value, position % self.capacity);
+        if written.is_err() {
+            self.write_errors.fetch_add(1, Relaxed);
+        }
+
+        let mut inner = self.inner.lock();
+        // The range may have been overwritten by later writes wrapping around the file.
+        if inner.index.get(&key).map(|record| record.position) != Some(position) {
+            return written.map(|_| false);
+        }
+        if let Err(err) = written {
+            inner.index.remove(&key);
+            inner.weight -= len;
+            return Err(err);
+        }
+        inner.index.get_mut(&key).unwrap().written = true;
+        self.insertions.fetch_add(1, Relaxed);
+        Ok(true)
+    }
+
+    /// Returns a snapshot of the statistics of the cache.
+    pub fn stats(&self) -> DiskCacheStats {
+        let inner = self.inner.lock();
+        DiskCacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            insertions: self.insertions.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            write_errors: self.write_errors.load(Relaxed),
+            len: inner.index.len() as u64,
+            weight: inner.weight,
+        }
+    }
+}
+
+/// Reads exactly `buf.len()` bytes at the given offset, without moving the cursor of the file.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.read_exact_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.seek_read(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes all of `buf` at the given offset, without moving the cursor of the file.
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.write_all_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut written = 0;
+        while written < buf.len() {
+            match file.seek_write(&buf[written..], offset + written as u64)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => written += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_insert_and_get() {
+        let dir = TempDir::new("disk_cache").unwrap();
+        let cache = DiskCache::open(&dir.path().join("cache"), 1024).unwrap();
+
+        assert!(cache.insert(1u64, b"one").unwrap());
+        assert!(!cache.insert(1u64, b"uno").unwrap());
+        assert!(!cache.insert(2u64, &[0; 2048]).unwrap());
+        assert_eq!(cache.get(&1), Some(Bytes::from_static(b"one")));
+        assert_eq!(cache.get(&2), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.weight, 3);
+    }
+
+    #[test]
+    fn test_oldest_values_are_overwritten() {
+        let dir = TempDir::new("disk_cache").unwrap();
+        let cache = DiskCache::open(&dir.path().join("cache"), 100).unwrap();
+
+        for i in 0..3u64 {
+            assert!(cache.insert(i, &[i as u8; 40]).unwrap());
+        }
+        // The third value wraps around to the start of the file, overwriting the first one.
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&1), Some(Bytes::from(vec![1; 40])));
+        assert_eq!(cache.get(&2), Some(Bytes::from(vec![2; 40])));
+
+        // The fourth value overwrites the second one, which sits where the fourth one starts.
+        assert!(cache.insert(3, &[3; 40]).unwrap());
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(Bytes::from(vec![2; 40])));
+        assert_eq!(cache.get(&3), Some(Bytes::from(vec![3; 40])));
+
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.len, 2);
+        assert_eq!(stats.weight, 80);
+    }
+
+    #[test]
+    fn test_corrupted_value_is_a_miss() {
+        let dir = TempDir::new("disk_cache").unwrap();
+        let path = dir.path().join("cache");
+        let cache = DiskCache::open(&path, 1024).unwrap();
+        cache.insert(1u64, b"value").unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(b"VALUE").unwrap();
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.stats().len, 0);
+        // The key can be cached again.
+        assert!(cache.insert(1, b"value").unwrap());
+        assert_eq!(cache.get(&1), Some(Bytes::from_static(b"value")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_write_errors_are_counted() {
+        // Every write to /dev/full fails with no space left on the device.
+        let cache = DiskCache::open(Path::new("/dev/full"), 1024).unwrap();
+
+        assert!(cache.insert(1u64, b"value").is_err());
+        assert_eq!(cache.get(&1), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.write_errors, 1);
+        assert_eq!(stats.insertions, 0);
+        assert_eq!(stats.len, 0);
+        assert_eq!(stats.weight, 0);
+    }
+
+    #[test]
+    fn test_concurrent_inserts_and_gets() {
+        let dir = TempDir::new("disk_cache").unwrap();
+        let cache = DiskCache::open(&dir.path().join("cache"), 4096).unwrap();
+
+        std::thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        let key = thread * 1000 + i;
+                        cache.insert(key, &key.to_le_bytes().repeat(8)).unwrap();
+                        // Values either read back intact or not at all.
+                        if let Some(value) = cache.get(&key) {
+                            assert_eq!(value, key.to_le_bytes().repeat(8));
+                        }
+                    }
+                });
+            }
+        });
+
+        let stats = cache.stats();
+        assert!(stats.weight <= 4096);
+        assert_eq!(stats.weight, stats.len * 64);
+    }
+}
@@ -0,0 +1,87 @@
+/// This is an adapter exposing `lru::LruCache` through the `Policy` trait, as the point of
+/// comparison used by the benches for every other policy.
+use ::lru::LruCache;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+
+use crate::storage::cache::{s3fifo::CacheStats, Policy};
+
+/// Cache is a least recently used cache holding up to a fixed number of entries, backed by
+/// `lru::LruCache`.
+pub struct Cache<K, V> {
+    inner: LruCache<K, V>,
+    stats: CacheStats,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Creates a new cache with the given maximum number of entries.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            inner: LruCache::new(capacity),
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+impl<K, V> Policy<K, V> for Cache<K, V>
+where
+    K: Hash + Eq,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let value = self.inner.get(key);
+        match value {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        value
+    }
+
+    fn insert(&mut self, key: K, value: V) -> bool {
+        if self.inner.contains(&key) {
+            return false;
+        }
+        self.inner.put(key, value);
+        self.stats.insertions += 1;
+        true
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.inner.pop(key)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        cache.get(&0);
+        cache.insert(2, 2);
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert!(!cache.insert(0, 10));
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.len(), 1);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 3);
+    }
+}
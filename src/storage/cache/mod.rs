@@ -1 +1,42 @@
+pub mod arc;
+pub mod clock;
+pub mod disk;
+pub mod lru;
+pub mod mrc;
+pub mod partitioned;
 pub mod s3fifo;
+pub mod sharded;
+pub mod sieve;
+pub mod trace;
+pub mod warm_start;
+
+use s3fifo::CacheStats;
+
+/// Policy is the interface shared by the eviction policies of this module, so that the value cache
+/// of the store and the benches can swap policies without duplicating code.
+///
+/// Every policy tracks the hits, misses and insertions of its `CacheStats`. The other statistics
+/// are specific to S3-FIFO and stay at zero for the other policies.
+pub trait Policy<K, V> {
+    /// Returns the value of the given key if it is in the cache, counting as an access.
+    fn get(&mut self, key: &K) -> Option<&V>;
+
+    /// Inserts a new entry, evicting other entries to make room for it.
+    /// Returns false if the key is already present, in which case its value is left unchanged, or
+    /// if the policy did not admit the entry.
+    fn insert(&mut self, key: K, value: V) -> bool;
+
+    /// Removes the given key from the cache, returning its value if it was present.
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Returns the number of entries in the cache.
+    fn len(&self) -> usize;
+
+    /// Returns true if the cache holds no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a snapshot of the statistics of the cache.
+    fn stats(&self) -> CacheStats;
+}
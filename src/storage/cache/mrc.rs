@@ -0,0 +1,432 @@
+/// Online estimation of miss-ratio curves with SHARDS, from "Efficient MRC Construction with
+/// SHARDS" by Carl A. Waldspurger, et al.
+///
+/// The estimator follows a spatially hashed sample of the keys looked up in a cache, and computes
+/// the reuse distance of each access to a sampled key: the total weight of the distinct sampled
+/// keys accessed since the previous access to it, scaled up by the sampling rate. An access hits
+/// in any LRU-like cache at least as large as its reuse distance, so the histogram of the
+/// distances gives the hit ratio the cache would have at any size.
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use std::collections::BinaryHeap;
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Multiples of the capacity of a cache at which its miss-ratio curve is reported.
+pub const MRC_SCALES: [f64; 6] = [0.25, 0.5, 1.0, 1.5, 2.0, 4.0];
+
+/// Number of bits of the hash of a key compared against the sampling threshold.
+const MODULUS_BITS: u32 = 24;
+
+/// Sampling threshold at which every key is sampled.
+const MODULUS: u64 = 1 << MODULUS_BITS;
+
+/// Number of buckets of the histogram per power of two, as a power of two.
+const SUB_BUCKET_BITS: u32 = 4;
+
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Number of buckets needed by the histogram to cover every `u64` distance.
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// Estimated miss-ratio curve of a cache, as the number of hits it would have had at each of
+/// `MRC_SCALES` times its current capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MissRatioCurve {
+    /// Number of lookups since the estimation was enabled.
+    pub lookups: u64,
+    /// Estimated number of hits at each of `MRC_SCALES` times the capacity.
+    pub hits: [u64; MRC_SCALES.len()],
+}
+
+impl MissRatioCurve {
+    /// Returns the estimated hit ratio at the given multiple of the capacity, if it is one of
+    /// `MRC_SCALES`.
+    pub fn hit_ratio(&self, scale: f64) -> Option<f64> {
+        let i = MRC_SCALES.iter().position(|&s| s == scale)?;
+        if self.lookups == 0 {
+            return Some(0.0);
+        }
+        Some(self.hits[i] as f64 / self.lookups as f64)
+    }
+
+    /// Returns the estimated miss ratio at the given multiple of the capacity, if it is one of
+    /// `MRC_SCALES`.
+    pub fn miss_ratio(&self, scale: f64) -> Option<f64> {
+        self.hit_ratio(scale).map(|hit_ratio| 1.0 - hit_ratio)
+    }
+
+    /// Returns the points of the curve, as pairs of a multiple of the capacity and the estimated
+    /// miss ratio at it.
+    pub fn points(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        MRC_SCALES
+            .iter()
+            .map(|&scale| (scale, self.miss_ratio(scale).unwrap()))
+    }
+}
+
+impl Add for MissRatioCurve {
+    type Output = MissRatioCurve;
+
+    fn add(self, other: MissRatioCurve) -> MissRatioCurve {
+        let mut hits = self.hits;
+        for (hits, other) in hits.iter_mut().zip(other.hits) {
+            *hits += other;
+        }
+        MissRatioCurve {
+            lookups: self.lookups + other.lookups,
+            hits,
+        }
+    }
+}
+
+/// A sampled key.
+struct Sample {
+    /// Logical time of the last access to the key.
+    time: usize,
+    weight: u64,
+}
+
+/// State of the estimator, guarded by its lock.
+struct State {
+    /// Sampled keys, by hash.
+    samples: HashMap<u64, Sample>,
+    /// Hashes of the sampled keys, largest first, to drop the largest when lowering the threshold.
+    hashes: BinaryHeap<u64>,
+    /// Fenwick tree over logical access times, holding the weight of every sampled key at the
+    /// time of its last access.
+    tree: Vec<u64>,
+    /// Logical time of the last access.
+    time: usize,
+    /// Number of sampled accesses per bucket of reuse distance.
+    histogram: Vec<f64>,
+    /// Number of sampled accesses, including those to keys seen for the first time.
+    accesses: f64,
+}
+
+impl State {
+    /// Adds `delta` to the weight at the given time, or subtracts it unless `add` is set.
+    fn update(&mut self, time: usize, delta: u64, add: bool) {
+        let mut i = time;
+        while i < self.tree.len() {
+            self.tree[i] = if add {
+                self.tree[i].wrapping_add(delta)
+            } else {
+                self.tree[i].wrapping_sub(delta)
+            };
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// Returns the total weight at times up to the given one.
+    fn prefix(&self, time: usize) -> u64 {
+        let mut sum = 0u64;
+        let mut i = time;
+        while i > 0 {
+            sum = sum.wrapping_add(self.tree[i]);
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+
+    /// Advances the logical time, renumbering the last accesses once the tree is full.
+    fn tick(&mut self) -> usize {
+        self.time += 1;
+        if self.time == self.tree.len() {
+            let mut samples: Vec<_> = self.samples.iter_mut().collect();
+            samples.sort_unstable_by_key(|(_, sample)| sample.time);
+            self.tree.iter_mut().for_each(|weight| *weight = 0);
+            let mut updates = Vec::with_capacity(samples.len());
+            for (time, (_, sample)) in samples.into_iter().enumerate() {
+                sample.time = time + 1;
+                updates.push((sample.time, sample.weight));
+            }
+            self.time = updates.len() + 1;
+            for (time, weight) in updates {
+                self.update(time, weight, true);
+            }
+        }
+        self.time
+    }
+
+    fn remove(&mut self, hash: u64) {
+        if let Some(sample) = self.samples.remove(&hash) {
+            self.update(sample.time, sample.weight, false);
+        }
+    }
+}
+
+/// MrcEstimator estimates the miss-ratio curve of a cache from its lookups, following at most a
+/// fixed number of keys. The sampling rate starts at one and is lowered whenever more keys are
+/// sampled, by dropping the keys with the largest hashes, so that memory use stays bounded.
+pub struct MrcEstimator {
+    /// Keys whose hash is below the threshold, out of `MODULUS`, are sampled.
+    threshold: AtomicU64,
+    /// Number of lookups, sampled or not.
+    lookups: AtomicU64,
+    max_samples: usize,
+    state: Mutex<State>,
+}
+
+impl MrcEstimator {
+    /// Creates a new estimator following at most `max_samples` keys.
+    pub fn new(max_samples: usize) -> Self {
+        let max_samples = max_samples.max(1);
+        Self {
+            threshold: AtomicU64::new(MODULUS),
+            lookups: AtomicU64::new(0),
+            max_samples,
+            state: Mutex::new(State {
+                samples: HashMap::new(),
+                hashes: BinaryHeap::new(),
+                tree: vec![0; 2 * max_samples + 1],
+                time: 0,
+                histogram: vec![0.0; BUCKETS],
+                accesses: 0.0,
+            }),
+        }
+    }
+
+    /// Records a lookup of the key with the given hash, with the weight of its entry on a hit.
+    /// The weight of a missed key is the one it was last seen with, or the one given to
+    /// `set_weight` once it is inserted.
+    pub fn access(&self, hash: u64, weight: Option<u64>) {
+        self.lookups.fetch_add(1, Relaxed);
+        if !self.is_sampled(hash) {
+            return;
+        }
+        let mut state = self.state.lock();
+        let threshold = self.threshold.load(Relaxed);
+        if !self.is_sampled(hash) {
+            return;
+        }
+        state.accesses += 1.0;
+        // The tick may renumber the accesses, so the last access is only looked up after it.
+        let time = state.tick();
+        match state.samples.get(&hash).map(|s| (s.time, s.weight)) {
+            Some((last_time, last_weight)) => {
+                let weight = weight.unwrap_or(last_weight);
+                // Only the weight of the other keys is sampled, and scaled up by the rate.
+                let others = state.prefix(time - 1).wrapping_sub(state.prefix(last_time));
+                let rate = threshold as f64 / MODULUS as f64;
+                let distance = (others as f64 / rate) as u64;
+                state.histogram[bucket(distance.saturating_add(weight))] += 1.0;
+                state.update(last_time, last_weight, false);
+                state.update(time, weight, true);
+                let sample = state.samples.get_mut(&hash).unwrap();
+                sample.time = time;
+                sample.weight = weight;
+            }
+            None => {
+                let weight = weight.unwrap_or(0);
+                state.samples.insert(hash, Sample { time, weight });
+                state.hashes.push(hash);
+                state.update(time, weight, true);
+                if state.samples.len() > self.max_samples {
+                    self.lower_threshold(&mut state);
+                }
+            }
+        }
+    }
+
+    /// Sets the weight of the key with the given hash, once it is inserted after a miss.
+    pub fn set_weight(&self, hash: u64, weight: u64) {
+        if !self.is_sampled(hash) {
+            return;
+        }
+        let mut state = self.state.lock();
+        let Some(sample) = state.samples.get_mut(&hash) else {
+            return;
+        };
+        let (time, old) = (sample.time, sample.weight);
+        sample.weight = weight;
+        state.update(time, old, false);
+        state.update(time, weight, true);
+    }
+
+    /// Returns the estimated fraction of lookups which would hit in a cache of the given capacity.
+    /// Distances are assumed to be evenly spread within the bucket holding the capacity.
+    pub fn hit_ratio(&self, capacity: u64) -> f64 {
+        let state = self.state.lock();
+        if state.accesses == 0.0 {
+            return 0.0;
+        }
+        let mut hits = 0.0;
+        for (i, &count) in state.histogram.iter().enumerate() {
+            let (start, end) = (bucket_start(i), bucket_end(i));
+            if start > capacity {
+                break;
+            }
+            let fits = capacity.min(end) - start + 1;
+            hits += count * fits as f64 / (end - start + 1) as f64;
+        }
+        hits / state.accesses
+    }
+
+    /// Returns the estimated miss-ratio curve of a cache of the given capacity.
+    pub fn curve(&self, capacity: u64) -> MissRatioCurve {
+        let lookups = self.lookups.load(Relaxed);
+        let hits = MRC_SCALES.map(|scale| {
+            let hit_ratio = self.hit_ratio((capacity as f64 * scale) as u64);
+            (hit_ratio * lookups as f64).round() as u64
+        });
+        MissRatioCurve { lookups, hits }
+    }
+
+    fn is_sampled(&self, hash: u64) -> bool {
+        hash >> (64 - MODULUS_BITS) < self.threshold.load(Relaxed)
+    }
+
+    /// Lowers the sampling threshold to stop sampling the key with the largest hash, and every
+    /// other key with the same sampled hash. The histogram is scaled down to the new rate.
+    fn lower_threshold(&self, state: &mut State) {
+        let old = self.threshold.load(Relaxed);
+        let Some(&largest) = state.hashes.peek() else {
+            return;
+        };
+        let threshold = largest >> (64 - MODULUS_BITS);
+        while let Some(&hash) = state.hashes.peek() {
+            if hash >> (64 - MODULUS_BITS) < threshold {
+                break;
+            }
+            state.hashes.pop();
+            state.remove(hash);
+        }
+        let ratio = threshold as f64 / old as f64;
+        state.histogram.iter_mut().for_each(|count| *count *= ratio);
+        state.accesses *= ratio;
+        self.threshold.store(threshold, Relaxed);
+    }
+}
+
+/// Returns the bucket of the histogram holding the given distance. Distances below
+/// `SUB_BUCKETS` get a bucket each, and every following power of two is split into
+/// `SUB_BUCKETS` buckets.
+fn bucket(distance: u64) -> usize {
+    if distance < SUB_BUCKETS {
+        return distance as usize;
+    }
+    let octave = 63 - distance.leading_zeros();
+    let sub = (distance >> (octave - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((octave - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// Returns the smallest distance held by the given bucket.
+fn bucket_start(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let octave = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    (SUB_BUCKETS + sub) << (octave - SUB_BUCKET_BITS)
+}
+
+/// Returns the largest distance held by the given bucket.
+fn bucket_end(bucket: usize) -> u64 {
+    match bucket + 1 {
+        BUCKETS => u64::MAX,
+        next => bucket_start(next) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash(key: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_buckets() {
+        for distance in [0, 1, 15, 16, 17, 31, 32, 1000, 1 << 40, u64::MAX] {
+            let i = bucket(distance);
+            assert!(bucket_start(i) <= distance && distance <= bucket_end(i));
+            assert!(i == 0 || bucket_start(i) == bucket_end(i - 1) + 1);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_loop_fits_above_its_length() {
+        // Looping over 8 keys only hits in caches holding all of them.
+        let estimator = MrcEstimator::new(1000);
+        for _ in 0..125 {
+            for key in 0..8 {
+                estimator.access(hash(key), Some(1));
+            }
+        }
+        assert_eq!(estimator.hit_ratio(7), 0.0);
+        assert_eq!(estimator.hit_ratio(8), 0.992);
+
+        let curve = estimator.curve(8);
+        assert_eq!(curve.lookups, 1000);
+        assert_eq!(curve.hit_ratio(0.5), Some(0.0));
+        assert_eq!(curve.hit_ratio(1.0), Some(0.992));
+        assert_eq!(curve.hits[4], 992);
+        assert_eq!(curve.hit_ratio(3.0), None);
+    }
+
+    #[test]
+    fn test_weights_count_in_distances() {
+        let estimator = MrcEstimator::new(1000);
+        // Misses learn the weight of the keys once they are inserted.
+        for key in 0..4 {
+            estimator.access(hash(key), None);
+            estimator.set_weight(hash(key), 3);
+        }
+        for key in 0..4 {
+            estimator.access(hash(key), Some(3));
+        }
+        assert_eq!(estimator.hit_ratio(11), 0.0);
+        assert_eq!(estimator.hit_ratio(12), 0.5);
+    }
+
+    #[test]
+    fn test_sampling_is_bounded() {
+        // Each key is accessed twice in a row, so every second access hits in any cache.
+        let estimator = MrcEstimator::new(64);
+        for key in 0..100_000 {
+            estimator.access(hash(key), Some(1));
+            estimator.access(hash(key), Some(1));
+        }
+        let state = estimator.state.lock();
+        assert!(state.samples.len() <= 64);
+        assert_eq!(state.samples.len(), state.hashes.len());
+        drop(state);
+        assert!(estimator.threshold.load(Relaxed) < MODULUS);
+
+        let hit_ratio = estimator.hit_ratio(1);
+        assert!((hit_ratio - 0.5).abs() < 0.05, "hit ratio {hit_ratio}");
+    }
+
+    #[test]
+    fn test_sampled_loop_estimate() {
+        // Looping over more keys than are sampled still estimates the size of the loop.
+        let estimator = MrcEstimator::new(256);
+        for _ in 0..20 {
+            for key in 0..10_000 {
+                estimator.access(hash(key), Some(1));
+            }
+        }
+        assert!(estimator.hit_ratio(8_000) < 0.1);
+        assert!(estimator.hit_ratio(12_000) > 0.85);
+    }
+
+    #[test]
+    fn test_curves_add_up() {
+        let a = MissRatioCurve {
+            lookups: 10,
+            hits: [1, 2, 3, 4, 5, 6],
+        };
+        let sum = a + a;
+        assert_eq!(sum.lookups, 20);
+        assert_eq!(sum.hits, [2, 4, 6, 8, 10, 12]);
+        assert_eq!(sum.hit_ratio(4.0), Some(0.6));
+    }
+}
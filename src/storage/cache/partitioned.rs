@@ -0,0 +1,384 @@
+/// A thread-safe S3-FIFO cache whose capacity is shared by several partitions, such as the tenants
+/// of a store, each with a guaranteed minimum and an upper limit.
+use parking_lot::{Mutex, RwLock};
+use std::borrow::Borrow;
+use std::cmp::{max, min};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use crate::storage::cache::s3fifo::{Cache, CacheStats, S3FifoConfig, UnitWeigher, Weigher};
+
+/// Identifier of a partition: its position in the quotas the cache was created with.
+pub type PartitionId = usize;
+
+/// Share of the capacity of a partitioned cache given to one partition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartitionQuota {
+    /// Weight the partition can always hold, whatever the other partitions do.
+    pub min_weight: u64,
+    /// Weight the partition never exceeds, even when the other partitions are idle.
+    pub max_weight: u64,
+}
+
+impl PartitionQuota {
+    /// Creates a new quota with the given guaranteed minimum and upper limit.
+    pub fn new(min_weight: u64, max_weight: u64) -> Self {
+        Self {
+            min_weight,
+            max_weight,
+        }
+    }
+}
+
+/// Snapshot of the statistics of a partition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartitionStats {
+    /// Statistics of the S3-FIFO cache of the partition.
+    pub cache: CacheStats,
+    /// Quota of the partition, clamped to the capacity of the cache.
+    pub quota: PartitionQuota,
+    /// Total weight of the entries of the partition.
+    pub weight: u64,
+    /// Weight held beyond the guaranteed minimum, borrowed from idle partitions.
+    pub borrowed: u64,
+    /// Number of entries evicted to give capacity back to other partitions.
+    pub reclaimed: u64,
+}
+
+struct Partition<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    cache: RwLock<Cache<K, V, W>>,
+    quota: PartitionQuota,
+    reclaimed: AtomicU64,
+}
+
+/// PartitionedCache splits its capacity between partitions, each of which is an S3-FIFO `Cache`
+/// guarded by its own `RwLock`, so that a partition scanning through many keys cannot evict the
+/// working set of the others.
+///
+/// Every partition can hold its minimum weight and never holds more than its maximum. In between,
+/// it borrows the capacity left idle by the other partitions. When the cache is full, a partition
+/// which is borrowing makes room with its own entries, while a partition within its minimum takes
+/// the capacity back from the partitions which borrow the most. The minimums should add up to at
+/// most the capacity of the cache, or they cannot all be guaranteed.
+///
+/// Keys belong to the partition they were inserted into, and are only looked up there. Hits only
+/// take the shared lock of their partition, while inserts are serialized as they may evict
+/// entries of any partition.
+pub struct PartitionedCache<K, V, W = UnitWeigher>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    partitions: Box<[Partition<K, V, W>]>,
+    max_weight: u64,
+    weigher: W,
+    /// Held by inserts while they make room and insert their entry.
+    insert_lock: Mutex<()>,
+}
+
+impl<K, V> PartitionedCache<K, V>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    /// Creates a new partitioned cache with the given maximum number of entries, with one
+    /// partition per quota.
+    pub fn new(max_cache_size: NonZeroUsize, quotas: &[PartitionQuota]) -> Self {
+        Self::with_weigher(max_cache_size.get() as u64, quotas, UnitWeigher)
+    }
+}
+
+impl<K, V, W> PartitionedCache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    W: Weigher<K, V> + Clone,
+{
+    /// Creates a new partitioned cache holding entries up to the given total weight, with one
+    /// partition per quota.
+    pub fn with_weigher(max_weight: u64, quotas: &[PartitionQuota], weigher: W) -> Self {
+        Self::with_weigher_and_config(max_weight, quotas, weigher, S3FifoConfig::default())
+    }
+
+    /// Creates a new partitioned cache holding entries up to the given total weight, with one
+    /// partition per quota and the given S3-FIFO parameters applied to every partition.
+    pub fn with_weigher_and_config(
+        max_weight: u64,
+        quotas: &[PartitionQuota],
+        weigher: W,
+        config: S3FifoConfig,
+    ) -> Self {
+        let max_weight = max(max_weight, 1);
+        let partitions = quotas
+            .iter()
+            .map(|quota| {
+                let quota_max = quota.max_weight.clamp(1, max_weight);
+                let quota = PartitionQuota::new(min(quota.min_weight, quota_max), quota_max);
+                Partition {
+                    cache: RwLock::new(Cache::with_weigher_and_config(
+                        quota.max_weight,
+                        weigher.clone(),
+                        config,
+                    )),
+                    quota,
+                    reclaimed: AtomicU64::new(0),
+                }
+            })
+            .collect();
+
+        Self {
+            partitions,
+            max_weight,
+            weigher,
+            insert_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the number of partitions.
+    pub fn partition_count(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Returns a clone of the value of the given key in the given partition.
+    /// Panics if the partition does not exist.
+    pub fn get<Q>(&self, partition: PartitionId, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.partitions[partition].cache.read().get(key).cloned()
+    }
+
+    /// Returns true if the given partition holds the given key, without counting as an access.
+    /// Panics if the partition does not exist.
+    pub fn contains_key<Q>(&self, partition: PartitionId, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.partitions[partition].cache.read().contains_key(key)
+    }
+
+    /// Inserts a new entry into the given partition, evicting entries of that partition or of the
+    /// partitions borrowing capacity to make room for it.
+    /// Returns false if the key is already present in the partition, the entry is heavier than
+    /// the maximum of the partition, or the admission filter rejects it.
+    /// Panics if the partition does not exist.
+    pub fn insert(&self, partition: PartitionId, key: K, value: V) -> bool {
+        let weight = max(self.weigher.weight(&key, &value), 1);
+        let _guard = self.insert_lock.lock();
+        let target = &self.partitions[partition];
+        if weight > target.quota.max_weight || target.cache.read().contains_key(&key) {
+            return false;
+        }
+
+        // Room to make within the maximum of the partition, and then within the whole cache.
+        let own_weight = target.cache.read().weight();
+        let mut own_target = min(own_weight, target.quota.max_weight - weight);
+        let total = self.weight() - (own_weight - own_target);
+        let mut needed = (total + weight).saturating_sub(self.max_weight);
+
+        // A partition beyond its minimum is borrowing, so it pays with its own entries first.
+        let own_borrowed = (own_target + weight).saturating_sub(target.quota.min_weight);
+        let from_own = min(needed, min(own_borrowed, own_target));
+        own_target -= from_own;
+        needed -= from_own;
+        if own_target < own_weight && !target.cache.write().shrink_to(own_target) {
+            return false;
+        }
+
+        // Take the rest back from the partitions which borrow the most.
+        while needed > 0 {
+            let victim = self
+                .partitions
+                .iter()
+                .enumerate()
+                .filter(|(id, _)| *id != partition)
+                .map(|(_, victim)| (victim, victim.cache.read().weight()))
+                .filter(|(victim, weight)| *weight > victim.quota.min_weight)
+                .max_by_key(|(victim, weight)| weight - victim.quota.min_weight);
+            let Some((victim, victim_weight)) = victim else {
+                break;
+            };
+            let borrowed = victim_weight - victim.quota.min_weight;
+            let mut cache = victim.cache.write();
+            let len = cache.len();
+            let shrunk = cache.shrink_to(victim_weight - min(needed, borrowed));
+            victim
+                .reclaimed
+                .fetch_add((len - cache.len()) as u64, Relaxed);
+            needed = needed.saturating_sub(victim_weight - cache.weight());
+            if !shrunk {
+                break;
+            }
+        }
+
+        let mut cache = target.cache.write();
+        // The minimums add up to more than the capacity, so the partition makes the rest of the
+        // room with its own entries.
+        let own_weight = cache.weight();
+        if needed > 0 && (own_weight < needed || !cache.shrink_to(own_weight - needed)) {
+            return false;
+        }
+        cache.insert(key, value)
+    }
+
+    /// Removes the given key from the given partition, returning its value if it was present.
+    /// Panics if the partition does not exist.
+    pub fn remove<Q>(&self, partition: PartitionId, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.partitions[partition].cache.write().remove(key)
+    }
+
+    /// Returns the statistics of the given partition.
+    /// Panics if the partition does not exist.
+    pub fn partition_stats(&self, partition: PartitionId) -> PartitionStats {
+        let partition = &self.partitions[partition];
+        let cache = partition.cache.read();
+        let weight = cache.weight();
+        PartitionStats {
+            cache: cache.stats(),
+            quota: partition.quota,
+            weight,
+            borrowed: weight.saturating_sub(partition.quota.min_weight),
+            reclaimed: partition.reclaimed.load(Relaxed),
+        }
+    }
+
+    /// Returns the statistics of the cache, summed over all partitions.
+    pub fn stats(&self) -> CacheStats {
+        self.partitions
+            .iter()
+            .map(|partition| partition.cache.read().stats())
+            .fold(CacheStats::default(), |total, stats| total + stats)
+    }
+
+    /// Returns the total weight of the entries in the cache.
+    pub fn weight(&self) -> u64 {
+        self.partitions
+            .iter()
+            .map(|partition| partition.cache.read().weight())
+            .sum()
+    }
+
+    /// Returns the number of entries in the cache, summed over all partitions.
+    pub fn len(&self) -> usize {
+        self.partitions
+            .iter()
+            .map(|partition| partition.cache.read().len())
+            .sum()
+    }
+
+    /// Returns true if no partition holds any entry.
+    pub fn is_empty(&self) -> bool {
+        self.partitions
+            .iter()
+            .all(|partition| partition.cache.read().is_empty())
+    }
+
+    /// Returns the maximum total weight of the entries in the cache.
+    pub fn capacity(&self) -> u64 {
+        self.max_weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partitioned(capacity: usize, quotas: &[(u64, u64)]) -> PartitionedCache<u64, u64> {
+        let quotas: Vec<PartitionQuota> = quotas
+            .iter()
+            .map(|&(min_weight, max_weight)| PartitionQuota::new(min_weight, max_weight))
+            .collect();
+        PartitionedCache::new(NonZeroUsize::new(capacity).unwrap(), &quotas)
+    }
+
+    #[test]
+    fn test_scan_does_not_evict_other_partitions() {
+        let cache = partitioned(100, &[(50, 100), (50, 100)]);
+        for i in 0..50 {
+            assert!(cache.insert(0, i, i));
+        }
+        // The second partition borrows nothing from the first, which is within its minimum.
+        for i in 0..1000 {
+            cache.insert(1, i, i);
+        }
+
+        for i in 0..50 {
+            assert_eq!(cache.get(0, &i), Some(i));
+        }
+        assert_eq!(cache.partition_stats(0).reclaimed, 0);
+        assert_eq!(cache.partition_stats(1).weight, 50);
+        assert_eq!(cache.weight(), 100);
+    }
+
+    #[test]
+    fn test_idle_capacity_is_borrowed_and_reclaimed() {
+        let cache = partitioned(100, &[(50, 100), (50, 100)]);
+        for i in 0..100 {
+            assert!(cache.insert(1, i, i));
+        }
+        let stats = cache.partition_stats(1);
+        assert_eq!(stats.weight, 100);
+        assert_eq!(stats.borrowed, 50);
+
+        // The first partition takes its minimum back, and no more.
+        for i in 0..80 {
+            assert!(cache.insert(0, i, i));
+        }
+        let stats = cache.partition_stats(0);
+        assert_eq!(stats.weight, 50);
+        assert_eq!(stats.borrowed, 0);
+        let stats = cache.partition_stats(1);
+        assert_eq!(stats.weight, 50);
+        assert_eq!(stats.reclaimed, 50);
+        assert_eq!(cache.len(), 100);
+    }
+
+    #[test]
+    fn test_partitions_stay_within_their_maximum() {
+        let cache = partitioned(100, &[(0, 20), (0, 100)]);
+        for i in 0..100 {
+            assert!(cache.insert(0, i, i));
+        }
+        assert_eq!(cache.partition_stats(0).weight, 20);
+        assert_eq!(cache.weight(), 20);
+
+        // Quotas are clamped to the capacity of the cache.
+        let cache = partitioned(10, &[(50, 100)]);
+        assert_eq!(cache.partition_stats(0).quota, PartitionQuota::new(10, 10));
+    }
+
+    #[test]
+    fn test_stats_per_partition() {
+        let cache = partitioned(100, &[(50, 100), (50, 100)]);
+        cache.insert(0, 1, 1);
+        cache.insert(1, 1, 10);
+        assert!(!cache.insert(0, 1, 2));
+
+        // Keys of different partitions are distinct.
+        assert_eq!(cache.get(0, &1), Some(1));
+        assert_eq!(cache.get(1, &1), Some(10));
+        assert_eq!(cache.get(1, &2), None);
+        assert_eq!(cache.remove(1, &1), Some(10));
+        assert!(!cache.contains_key(1, &1));
+
+        let stats = cache.partition_stats(0);
+        assert_eq!((stats.cache.hits, stats.cache.misses), (1, 0));
+        let stats = cache.partition_stats(1);
+        assert_eq!((stats.cache.hits, stats.cache.misses), (1, 1));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.insertions), (2, 1, 2));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -1,18 +1,422 @@
-/// This is an experimental implementation of a cache that uses the S3-FIFO algorithm. This is not yet
-/// used in the main codebase. But the implementation is kept here for future reference for replacing it
-/// with the current LRU cache for caching recently accessed values.
-use hashbrown::HashMap;
-use std::fmt::Debug;
-use std::hash::Hash;
-use std::sync::atomic::AtomicU8;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+/// This is an implementation of a cache that uses the S3-FIFO algorithm. It can be selected as the
+/// value cache of the store through `Options::cache_policy`.
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::{HashMap, HashTable};
+use std::borrow::Borrow;
 use std::cmp::max;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fmt::{self, Debug};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+use std::io;
+use std::mem;
 use std::num::NonZeroUsize;
-use ringbuf::{HeapRb, Rb};
-use indexmap::IndexSet;
+use std::ops::Add;
+use std::path::Path;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU64, AtomicU8};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::storage::cache::mrc::{MissRatioCurve, MrcEstimator};
+use crate::storage::cache::warm_start::{Persist, WarmState};
+use crate::storage::cache::Policy;
+
+/// Default maximum frequency limit for an entry in the cache.
+const DEFAULT_MAX_FREQUENCY: u8 = 3;
+
+/// Default share of the capacity given to the small queue.
+const DEFAULT_SMALL_RATIO: f64 = 0.1;
+
+/// Default capacity of the ghost queue, relative to the capacity of the cache.
+const DEFAULT_GHOST_RATIO: f64 = 0.9;
+
+/// Default frequency an entry needs to be promoted from the small queue into the main queue.
+const DEFAULT_PROMOTION_THRESHOLD: u8 = 1;
+
+/// Default number of promotions and reinsertions a single eviction may perform.
+const DEFAULT_EVICTION_BUDGET: u32 = 32;
+
+/// Default share of the capacity pinned entries may take up.
+const DEFAULT_MAX_PINNED_RATIO: f64 = 0.5;
+
+/// Default refetch cost worth one extra lap in the main queue, e.g. a page when costs are bytes.
+const DEFAULT_COST_UNIT: u64 = 4096;
+
+/// Smallest share of the capacity the adaptive mode shrinks the small queue to.
+const MIN_ADAPTIVE_SMALL_RATIO: f64 = 0.01;
+
+/// Largest share of the capacity the adaptive mode grows the small queue to.
+const MAX_ADAPTIVE_SMALL_RATIO: f64 = 0.5;
+
+/// Share of the capacity the adaptive mode moves the small queue budget by at a time.
+const ADAPTIVE_STEP_RATIO: f64 = 0.01;
+
+/// Number of rows of the frequency sketch of the admission filter.
+const SKETCH_DEPTH: usize = 4;
+
+/// Odd multipliers mapping a hash to a different counter in every row of the frequency sketch.
+const SKETCH_SEEDS: [u64; SKETCH_DEPTH] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x85EB_CA77_C2B2_AE63,
+];
+
+/// Bounds of the number of counters in a row of the frequency sketch.
+const MIN_SKETCH_WIDTH: u64 = 1 << 10;
+const MAX_SKETCH_WIDTH: u64 = 1 << 20;
+
+/// Value at which the counters of the frequency sketch saturate.
+const MAX_SKETCH_COUNT: u8 = 15;
+
+/// Number of recorded accesses per counter of a row after which the sketch is aged.
+const SKETCH_SAMPLE_FACTOR: u64 = 10;
+
+/// Represents the tunable parameters of the S3-FIFO algorithm.
+///
+/// The defaults follow the paper: the small queue gets 10% of the capacity, the ghost queue
+/// remembers as many keys as fit in the main queue, frequencies saturate at 3, and a single access
+/// while in the small queue is enough to be promoted into the main queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct S3FifoConfig {
+    /// The share of the capacity given to the small queue.
+    ///
+    /// Values are clamped to `[0.0, 1.0]`. The small queue always gets a weight of at least one.
+    pub(crate) small_ratio: f64,
+
+    /// The capacity of the ghost queue, relative to the capacity of the cache.
+    ///
+    /// Negative values are treated as zero, which disables the ghost queue.
+    pub(crate) ghost_ratio: f64,
+
+    /// The frequency at which the access counter of an entry saturates.
+    ///
+    /// This bounds how many times an entry is reinserted into the main queue without being
+    /// accessed again. Values are clamped to at least one.
+    pub(crate) max_freq: u8,
+
+    /// The frequency an entry needs when it reaches the head of the small queue to be promoted into
+    /// the main queue instead of being evicted.
+    ///
+    /// Values are clamped to `[1, max_freq]`.
+    pub(crate) promotion_threshold: u8,
+
+    /// Whether the budget of the small queue is tuned at runtime, starting from `small_ratio`.
+    ///
+    /// The budget grows when keys come back from the ghost queue more often than entries are
+    /// promoted out of the small queue, and shrinks otherwise. It stays between 1% and 50% of the
+    /// capacity, widened to include the initial `small_ratio`.
+    pub(crate) adaptive: bool,
+
+    /// The number of promotions out of the small queue and reinsertions into the main queue a
+    /// single eviction may perform before it gives up looking for a cold entry.
+    ///
+    /// Once the budget is spent, the entry at the head of the queue being evicted from leaves the
+    /// cache whatever its frequency. Zero turns both queues into plain FIFOs.
+    pub(crate) eviction_budget: u32,
+
+    /// Whether new keys go through a TinyLFU admission filter once the cache is full.
+    ///
+    /// The filter estimates how often keys were accessed with a count-min sketch, and only admits
+    /// a new key if it was accessed more often than the entry it would push out. Keys coming back
+    /// from the ghost queue are always admitted.
+    pub(crate) admission_filter: bool,
+
+    /// The share of the capacity pinned entries may take up. Pinning beyond it fails.
+    ///
+    /// Values are clamped to `[0.0, 1.0]`.
+    pub(crate) max_pinned_ratio: f64,
+
+    /// The refetch cost worth one extra lap in the main queue, for entries inserted with a cost.
+    ///
+    /// An entry with a cost of `cost` may be reinserted `log2(cost / cost_unit + 1)` more times,
+    /// up to `max_freq`, once its frequency ran out. Values are clamped to at least one.
+    pub(crate) cost_unit: u64,
+}
+
+impl Default for S3FifoConfig {
+    fn default() -> Self {
+        Self {
+            small_ratio: DEFAULT_SMALL_RATIO,
+            ghost_ratio: DEFAULT_GHOST_RATIO,
+            max_freq: DEFAULT_MAX_FREQUENCY,
+            promotion_threshold: DEFAULT_PROMOTION_THRESHOLD,
+            adaptive: false,
+            eviction_budget: DEFAULT_EVICTION_BUDGET,
+            admission_filter: false,
+            max_pinned_ratio: DEFAULT_MAX_PINNED_RATIO,
+            cost_unit: DEFAULT_COST_UNIT,
+        }
+    }
+}
+
+impl S3FifoConfig {
+    /// Creates a new configuration with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_small_ratio(mut self, small_ratio: f64) -> Self {
+        self.small_ratio = small_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_ghost_ratio(mut self, ghost_ratio: f64) -> Self {
+        self.ghost_ratio = ghost_ratio.max(0.0);
+        self
+    }
+
+    pub fn with_max_freq(mut self, max_freq: u8) -> Self {
+        self.max_freq = max(max_freq, 1);
+        self.promotion_threshold = self.promotion_threshold.min(self.max_freq);
+        self
+    }
+
+    pub fn with_promotion_threshold(mut self, promotion_threshold: u8) -> Self {
+        self.promotion_threshold = promotion_threshold.clamp(1, self.max_freq);
+        self
+    }
+
+    pub fn with_adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    pub fn with_eviction_budget(mut self, eviction_budget: u32) -> Self {
+        self.eviction_budget = eviction_budget;
+        self
+    }
+
+    pub fn with_admission_filter(mut self, admission_filter: bool) -> Self {
+        self.admission_filter = admission_filter;
+        self
+    }
+
+    pub fn with_max_pinned_ratio(mut self, max_pinned_ratio: f64) -> Self {
+        self.max_pinned_ratio = max_pinned_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_cost_unit(mut self, cost_unit: u64) -> Self {
+        self.cost_unit = max(cost_unit, 1);
+        self
+    }
+}
+
+/// Error returned when an entry cannot be pinned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinError {
+    /// The key is not in the cache.
+    NotFound,
+    /// Pinning the entry would take the pinned entries over their share of the capacity, as set by
+    /// `S3FifoConfig::with_max_pinned_ratio`.
+    LimitExceeded,
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinError::NotFound => write!(f, "Key not found"),
+            PinError::LimitExceeded => write!(f, "Pinned entries limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for PinError {}
+
+/// Weigher computes the weight of an entry, which is what the capacity of the cache is measured in.
+/// A weight of zero is treated as one, so that every entry takes up some capacity.
+pub trait Weigher<K, V> {
+    /// Returns the weight of the entry with the given key and value.
+    fn weight(&self, key: &K, value: &V) -> u64;
+}
+
+/// Weigher giving every entry a weight of one, so that the capacity counts entries.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnitWeigher;
 
-/// Maximum frequency limit for an entry in the cache.
-const MAX_FREQUENCY_LIMIT: u8 = 3;
+impl<K, V> Weigher<K, V> for UnitWeigher {
+    fn weight(&self, _key: &K, _value: &V) -> u64 {
+        1
+    }
+}
+
+/// Clock is the source of time used to expire entries. It can be replaced to make expiry
+/// deterministic in tests.
+pub trait Clock: Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+}
+
+/// Clock reading the monotonic system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Reason for an entry leaving the cache, as reported to an `EvictionListener`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionCause {
+    /// Evicted from the small queue without being accessed. Its key moves to the ghost queue.
+    SmallEvicted,
+    /// Evicted from the main queue after its frequency ran out.
+    MainEvicted,
+    /// Removed explicitly through `remove` or `invalidate_if`.
+    Removed,
+    /// Dropped after its time to live elapsed.
+    Expired,
+    /// Its value was replaced through `insert_or_update` or `replace`. The listener receives the
+    /// previous value.
+    Replaced,
+}
+
+/// EvictionListener is notified of every entry leaving the cache, before its value is dropped.
+/// The listener is called while the cache is being modified, so it must not call back into it.
+pub trait EvictionListener<K, V>: Send + Sync {
+    /// Called with the key and value of an entry leaving the cache and the reason it left.
+    fn on_evict(&self, key: &K, value: &V, cause: EvictionCause);
+}
+
+impl<K, V, F> EvictionListener<K, V> for F
+where
+    F: Fn(&K, &V, EvictionCause) + Send + Sync,
+{
+    fn on_evict(&self, key: &K, value: &V, cause: EvictionCause) {
+        self(key, value, cause)
+    }
+}
+
+/// Snapshot of the statistics of a cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of lookups which found a live entry.
+    pub hits: u64,
+    /// Number of lookups which found no live entry.
+    pub misses: u64,
+    /// Total refetch cost of the entries found by lookups, i.e. the cost saved by the cache. With
+    /// costs given in bytes read, as the store does, this is the number of bytes saved.
+    pub bytes_saved: u64,
+    /// Number of entries admitted into the cache.
+    pub insertions: u64,
+    /// Number of entries moved from the small queue into the main queue.
+    pub promotions: u64,
+    /// Number of insertions sent straight into the main queue because the key was in the ghost
+    /// queue.
+    pub ghost_hits: u64,
+    /// Number of times an entry was reinserted at the tail of the main queue.
+    pub main_reinsertions: u64,
+    /// Number of entries evicted from the small queue into the ghost queue.
+    pub small_evictions: u64,
+    /// Number of entries evicted from the main queue.
+    pub main_evictions: u64,
+    /// Number of expired entries dropped from the cache.
+    pub expirations: u64,
+    /// Number of entries evicted despite their frequency because the eviction budget ran out.
+    pub forced_evictions: u64,
+    /// Number of new keys let into a full cache by the admission filter because they were accessed
+    /// more often than the entry they would evict.
+    pub admitted: u64,
+    /// Number of new keys kept out of a full cache by the admission filter.
+    pub rejected: u64,
+    /// Number of entries currently in the small queue.
+    pub small_len: u64,
+    /// Number of entries currently in the main queue.
+    pub main_len: u64,
+    /// Number of keys currently in the ghost queue.
+    pub ghost_len: u64,
+    /// Total weight of the entries currently in the small queue.
+    pub small_weight: u64,
+    /// Total weight of the entries currently in the main queue.
+    pub main_weight: u64,
+    /// Current weight budget of the small queue, which changes over time in adaptive mode.
+    pub small_capacity: u64,
+    /// Total weight of the pinned entries.
+    pub pinned_weight: u64,
+    /// Estimated miss-ratio curve, if its estimation is enabled.
+    pub mrc: Option<MissRatioCurve>,
+}
+
+impl CacheStats {
+    /// Returns the fraction of lookups which were hits, or zero if there were no lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+impl Add for CacheStats {
+    type Output = CacheStats;
+
+    fn add(self, other: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            bytes_saved: self.bytes_saved + other.bytes_saved,
+            insertions: self.insertions + other.insertions,
+            promotions: self.promotions + other.promotions,
+            ghost_hits: self.ghost_hits + other.ghost_hits,
+            main_reinsertions: self.main_reinsertions + other.main_reinsertions,
+            small_evictions: self.small_evictions + other.small_evictions,
+            main_evictions: self.main_evictions + other.main_evictions,
+            expirations: self.expirations + other.expirations,
+            forced_evictions: self.forced_evictions + other.forced_evictions,
+            admitted: self.admitted + other.admitted,
+            rejected: self.rejected + other.rejected,
+            small_len: self.small_len + other.small_len,
+            main_len: self.main_len + other.main_len,
+            ghost_len: self.ghost_len + other.ghost_len,
+            small_weight: self.small_weight + other.small_weight,
+            main_weight: self.main_weight + other.main_weight,
+            small_capacity: self.small_capacity + other.small_capacity,
+            pinned_weight: self.pinned_weight + other.pinned_weight,
+            mrc: match (self.mrc, other.mrc) {
+                (Some(mrc), Some(other)) => Some(mrc + other),
+                (mrc, other) => mrc.or(other),
+            },
+        }
+    }
+}
+
+/// Counters backing `CacheStats`. They are relaxed atomics so that they can be bumped by lookups,
+/// which only have shared access to the cache.
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_saved: AtomicU64,
+    insertions: AtomicU64,
+    promotions: AtomicU64,
+    ghost_hits: AtomicU64,
+    main_reinsertions: AtomicU64,
+    small_evictions: AtomicU64,
+    main_evictions: AtomicU64,
+    expirations: AtomicU64,
+    forced_evictions: AtomicU64,
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Counters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Relaxed);
+    }
+}
+
+/// The queue an entry currently lives in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Queue {
+    Small,
+    Main,
+}
 
 /// Represents an entry in the cache.
 #[derive(Debug)]
@@ -21,278 +425,2699 @@ struct Entry<K, V> {
     value: V,
     /// Frequency of access of this entry.
     freq: AtomicU8,
+    /// Weight of this entry as computed by the weigher of the cache.
+    weight: u64,
+    /// Queue this entry currently lives in.
+    queue: Queue,
+    /// Instant after which this entry is expired, if it has a time to live.
+    expires_at: Option<Instant>,
+    /// Whether this entry is pinned, and never evicted.
+    pinned: bool,
+    /// Cost of refetching this entry on a miss, in the unit chosen by the caller.
+    cost: u64,
+    /// Extra laps this entry may still do in the main queue once its frequency ran out, earned
+    /// through its cost.
+    credits: u8,
 }
 
 impl<K, V> Entry<K, V> {
-    /// Creates a new entry with the given key and value.
-    pub fn new(key: K, value: V) -> Self {
+    /// Creates a new entry with the given key, value, weight, queue and expiry.
+    pub fn new(key: K, value: V, weight: u64, queue: Queue, expires_at: Option<Instant>) -> Self {
         Self {
             key,
             value,
             freq: AtomicU8::new(0),
+            weight,
+            queue,
+            expires_at,
+            pinned: false,
+            cost: 0,
+            credits: 0,
         }
     }
+
+    /// Returns true if this entry is expired at the given instant.
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
 }
 
 impl<K, V> Clone for Entry<K, V>
-    where
-        K: Clone,
-        V: Clone,
+where
+    K: Clone,
+    V: Clone,
 {
     fn clone(&self) -> Self {
         Self {
             key: self.key.clone(),
             value: self.value.clone(),
             freq: AtomicU8::new(self.freq.load(Relaxed)),
+            weight: self.weight,
+            queue: self.queue,
+            expires_at: self.expires_at,
+            pinned: self.pinned,
+            cost: self.cost,
+            credits: self.credits,
+        }
+    }
+}
+
+/// Tunes the weight budget of the small queue at runtime, in the spirit of how ARC balances its
+/// lists.
+///
+/// A ghost hit means that an entry left the small queue too early and was needed again, so the
+/// small queue should grow. A promotion means that the small queue caught a reuse in time, so it
+/// can shrink and leave more room to the main queue. Once the weight leaving the small queue adds
+/// up to its budget, the budget moves by one step towards whichever signal dominated.
+///
+/// Only the budget changes: no entry is moved when it does. A small queue that is over its new
+/// budget is drained by the regular evictions, one entry at a time.
+#[derive(Debug)]
+struct SmallQueueTuner {
+    /// Lower bound of the budget.
+    min_weight: u64,
+    /// Upper bound of the budget.
+    max_weight: u64,
+    /// Amount the budget moves by at a time.
+    step: u64,
+    /// Ghost hits seen in the current window.
+    ghost_hits: u64,
+    /// Promotions seen in the current window.
+    promotions: u64,
+    /// Weight which left the small queue in the current window.
+    departed_weight: u64,
+}
+
+impl SmallQueueTuner {
+    fn new(max_weight: u64, initial_weight: u64) -> Self {
+        let ratio_of = |ratio: f64| max((max_weight as f64 * ratio) as u64, 1);
+        Self {
+            min_weight: ratio_of(MIN_ADAPTIVE_SMALL_RATIO).min(initial_weight),
+            max_weight: ratio_of(MAX_ADAPTIVE_SMALL_RATIO).max(initial_weight),
+            step: ratio_of(ADAPTIVE_STEP_RATIO),
+            ghost_hits: 0,
+            promotions: 0,
+            departed_weight: 0,
+        }
+    }
+
+    fn record_ghost_hit(&mut self) {
+        self.ghost_hits += 1;
+    }
+
+    fn record_departure(&mut self, weight: u64, promoted: bool) {
+        self.departed_weight += weight;
+        if promoted {
+            self.promotions += 1;
+        }
+    }
+
+    /// Returns the next budget of the small queue once the current window is over.
+    fn next_budget(&mut self, budget: u64) -> Option<u64> {
+        if self.departed_weight < budget {
+            return None;
         }
+        let next = if self.ghost_hits > self.promotions {
+            budget.saturating_add(self.step).min(self.max_weight)
+        } else if self.ghost_hits < self.promotions {
+            budget.saturating_sub(self.step).max(self.min_weight)
+        } else {
+            budget
+        };
+        self.ghost_hits = 0;
+        self.promotions = 0;
+        self.departed_weight = 0;
+        Some(next)
     }
 }
 
-/// Used for ghost queue allowing constant access time while retaining insertion order.
-struct GhostQueue<K> {
-    queue: IndexSet<K>,
-    capacity: usize,
+/// Returns the 32-bit fingerprint of the given key, taken from the high bits of a hash which is the
+/// same in every process running the same build, unlike the randomly seeded hashers of maps.
+pub(crate) fn fingerprint<K: Hash + ?Sized>(key: &K) -> u32 {
+    (BuildHasherDefault::<DefaultHasher>::default().hash_one(key) >> 32) as u32
+}
+
+/// GhostQueue remembers recently evicted keys by a 32-bit fingerprint of their hash rather than
+/// the keys themselves, so its memory use does not depend on the size of the keys.
+///
+/// Fingerprints are kept in a ring buffer in eviction order, next to an index counting the slots
+/// of every fingerprint, which gives constant time lookups. The oldest fingerprints are dropped
+/// first once the total weight of the remembered keys exceeds the capacity. A key evicted again
+/// while still remembered takes a second slot, and stays remembered until its last slot is dropped.
+///
+/// Fingerprints come from `fingerprint`, which does not depend on the process, so that they can be
+/// persisted in warm-start files.
+///
+/// Two keys with the same fingerprint cannot be told apart, so a lookup for a key which was never
+/// evicted matches with a probability of about `len / 2^32` when the queue remembers `len`
+/// distinct fingerprints, i.e. below 0.03% for a million keys. A false positive only sends a new
+/// entry straight into the main queue.
+struct GhostQueue {
+    ring: VecDeque<(u32, u64)>,
+    index: HashMap<u32, u32>,
+    weight: u64,
+    capacity: u64,
 }
 
-impl<K: Hash + Eq + PartialEq + Clone> GhostQueue<K> {
-    fn new(size: usize) -> Self {
+impl GhostQueue {
+    fn new(capacity: u64) -> Self {
         Self {
-            queue: IndexSet::with_capacity(size),
-            capacity: size,
+            ring: VecDeque::new(),
+            index: HashMap::new(),
+            weight: 0,
+            capacity,
         }
     }
 
-    /// Maintain queue size by evicting before insertion if at capacity.
-    fn push(&mut self, key: K) {
-        if self.queue.len() == self.capacity {
-            self.evict()
+    fn push<K: Hash + ?Sized>(&mut self, key: &K, weight: u64) {
+        self.push_fingerprint(fingerprint(key), weight);
+    }
+
+    /// Maintain queue weight by evicting the oldest fingerprints before insertion while over
+    /// capacity.
+    fn push_fingerprint(&mut self, fingerprint: u32, weight: u64) {
+        if weight > self.capacity {
+            return;
+        }
+        while self.weight + weight > self.capacity {
+            self.evict();
         }
-        self.queue.insert(key);
+        self.ring.push_back((fingerprint, weight));
+        *self.index.entry(fingerprint).or_insert(0) += 1;
+        self.weight += weight;
     }
 
     fn evict(&mut self) {
-        self.queue.pop();
+        let Some((fingerprint, weight)) = self.ring.pop_front() else {
+            return;
+        };
+        self.weight -= weight;
+        if let Some(slots) = self.index.get_mut(&fingerprint) {
+            *slots -= 1;
+            if *slots == 0 {
+                self.index.remove(&fingerprint);
+            }
+        }
+    }
+
+    fn contains<K: Hash + ?Sized>(&self, key: &K) -> bool {
+        self.index.contains_key(&fingerprint(key))
+    }
+
+    fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Changes the capacity of the queue, dropping the oldest fingerprints until it fits.
+    fn resize(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        while self.weight > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Returns the fingerprints and weights of the queue, from oldest to newest.
+    fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.ring.iter().copied()
+    }
+
+    fn clear(&mut self) {
+        self.ring.clear();
+        self.index.clear();
+        self.weight = 0;
+    }
+}
+
+/// FrequencySketch is a count-min sketch estimating how often keys were accessed, used by the
+/// TinyLFU admission filter.
+///
+/// Every key maps to one saturating counter in each of `SKETCH_DEPTH` rows, and its estimate is
+/// the smallest of them. Once the number of recorded accesses reaches ten times the width of a
+/// row, every counter is halved, so that the sketch follows changes in popularity instead of
+/// remembering keys which were hot a long time ago.
+///
+/// Counters are relaxed atomics so that hits, which only have shared access to the cache, can
+/// record accesses. Concurrent updates may lose an increment, which only makes the estimates a
+/// little less precise.
+struct FrequencySketch {
+    counters: Box<[AtomicU8]>,
+    /// Mask of the column bits of a hash, as the width of a row is a power of two.
+    mask: u64,
+    /// Number of recorded accesses since the last aging, plus the half kept by every aging.
+    additions: AtomicU64,
+    sample_size: u64,
+}
+
+impl FrequencySketch {
+    /// Creates a sketch with one counter per unit of weight of the given capacity, within bounds.
+    fn new(capacity: u64) -> Self {
+        let width = capacity
+            .clamp(MIN_SKETCH_WIDTH, MAX_SKETCH_WIDTH)
+            .next_power_of_two();
+        Self {
+            counters: (0..width as usize * SKETCH_DEPTH)
+                .map(|_| AtomicU8::new(0))
+                .collect(),
+            mask: width - 1,
+            additions: AtomicU64::new(0),
+            sample_size: width * SKETCH_SAMPLE_FACTOR,
+        }
+    }
+
+    /// Returns the counter of the given hash in the given row.
+    fn counter(&self, hash: u64, row: usize) -> &AtomicU8 {
+        // Every row scrambles the hash with its own odd multiplier and keeps the high bits.
+        let column = (hash.wrapping_mul(SKETCH_SEEDS[row]) >> 32) & self.mask;
+        &self.counters[row * (self.mask as usize + 1) + column as usize]
+    }
+
+    /// Records an access to the key with the given hash.
+    fn increment(&self, hash: u64) {
+        let mut incremented = false;
+        for row in 0..SKETCH_DEPTH {
+            incremented |= self
+                .counter(hash, row)
+                .fetch_update(Relaxed, Relaxed, |count| {
+                    (count < MAX_SKETCH_COUNT).then_some(count + 1)
+                })
+                .is_ok();
+        }
+        if incremented && self.additions.fetch_add(1, Relaxed) + 1 == self.sample_size {
+            self.age();
+        }
     }
 
-    fn contains(&self, key: &K) -> bool {
-        self.queue.contains(key)
+    /// Returns the estimated number of accesses to the key with the given hash.
+    fn estimate(&self, hash: u64) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counter(hash, row).load(Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Halves every counter and the number of recorded accesses.
+    fn age(&self) {
+        self.additions.fetch_sub(self.sample_size / 2, Relaxed);
+        for counter in self.counters.iter() {
+            let _ = counter.fetch_update(Relaxed, Relaxed, |count| Some(count / 2));
+        }
     }
 }
 
 /// Cache is an implementation of "S3-FIFO" from "FIFO Queues are ALL You Need for Cache Eviction" by
 /// Juncheng Yang, et al. <https://jasony.me/publication/sosp23-s3fifo.pdf>
-pub struct Cache<K, V>
-    where
-        K: PartialEq + Eq + Hash + Clone + Debug,
-        V: Clone + Debug,
+///
+/// The capacity of the cache is a total weight, as computed by its `Weigher`. The small queue is
+/// given a share of the capacity (10% by default, see `S3FifoConfig`) and the main queue the rest.
+/// Entries that are heavier than the whole capacity are never admitted, and entries that are
+/// heavier than the small queue budget skip the small queue and are inserted straight into the
+/// main queue.
+///
+/// Entries live in an arena of slots, and the queues only hold `u32` slot indices, so every key is
+/// stored once and moving an entry between queues never allocates. A hash table maps keys to their
+/// slots. Removing an entry empties its slot but leaves its index behind in its queue as a stale
+/// index, which is skipped when it reaches the head of the queue. The slot is only reused once its
+/// stale index has left the queue. A queue is compacted once more than half of its indices are
+/// stale.
+///
+/// Evictions are bounded: a single eviction performs at most `S3FifoConfig::eviction_budget`
+/// promotions and reinsertions before forcing the entry at the head of its queue out, and skips at
+/// most about half a queue of stale indices. An insert performs one eviction per entry that has to
+/// leave to make room for it, which is a single one with unit weights, and a removal may compact
+/// a queue in time linear in its length.
+///
+/// Entries can be given a time to live, either per entry or through a cache-wide default. Expired
+/// entries are treated as misses, and are dropped once they reach the head of their queue instead
+/// of being promoted or reinserted.
+///
+/// An optional TinyLFU admission filter (see `S3FifoConfig::admission_filter`) keeps one-hit
+/// wonders and scans out of a full cache: a new key is only inserted if it was accessed more often
+/// than the entry its insertion would evict first. Hits and insertions of new keys count as
+/// accesses.
+///
+/// Entries can be pinned, up to a share of the capacity (see `S3FifoConfig::max_pinned_ratio`).
+/// Pinned entries count against the capacity but are never evicted: reaching the head of the small
+/// queue moves them on to the main queue, and reaching the head of the main queue moves them back
+/// to its tail, in both cases without spending their frequency. They still expire, and can be
+/// removed explicitly.
+///
+/// Entries can be inserted with the cost of refetching them on a miss, through `insert_with_cost`.
+/// Expensive entries earn credits which let them do extra laps in the main queue once their
+/// frequency ran out (see `S3FifoConfig::cost_unit`), so that the cache lowers the total refetch
+/// cost of its misses rather than only their number. The cost of every hit adds up in
+/// `CacheStats::bytes_saved`.
+///
+/// The miss-ratio curve of the cache can be estimated from its lookups, by enabling it at runtime
+/// with `enable_mrc`. `CacheStats::mrc` then reports the hit ratio the cache would have at a few
+/// multiples of its current capacity, to size it from live traffic.
+pub struct Cache<K, V, W = UnitWeigher>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
 {
     /// Small queue for entries with low frequency.
-    small: HeapRb<K>,
+    small: VecDeque<u32>,
     /// Main queue for entries with high frequency.
-    main: HeapRb<K>,
+    main: VecDeque<u32>,
     /// Ghost queue for evicted entry keys.
-    ghost: GhostQueue<K>,
-    /// Map of all entries for quick access to data.
-    entries: HashMap<K, Entry<K, V>>,
+    ghost: GhostQueue,
+    /// Arena of entries, addressed by slot index. The slot of a removed entry is empty.
+    slots: Vec<Option<Entry<K, V>>>,
+    /// Empty slots which are not referenced by any queue.
+    free: Vec<u32>,
+    /// Index from the keys of the entries to their slots.
+    index: HashTable<u32>,
+    /// Hasher of the keys in the index.
+    hash_builder: DefaultHashBuilder,
+    /// Weigher used to compute the weight of new entries.
+    weigher: W,
+    /// Maximum total weight of the entries in the cache.
+    max_weight: u64,
+    /// Weight budget of the small queue.
+    max_small_weight: u64,
+    /// Share of the capacity given to the small queue, outside of adaptive mode.
+    small_ratio: f64,
+    /// Capacity of the ghost queue, relative to the capacity of the cache.
+    ghost_ratio: f64,
+    /// Frequency at which access counters saturate.
+    max_freq: u8,
+    /// Frequency needed to be promoted from the small queue into the main queue.
+    promotion_threshold: u8,
+    /// Tuner of the small queue budget, set in adaptive mode.
+    tuner: Option<SmallQueueTuner>,
+    /// Number of promotions and reinsertions a single eviction may perform.
+    eviction_budget: u32,
+    /// Frequency sketch of the admission filter, if enabled.
+    sketch: Option<FrequencySketch>,
+    /// Share of the capacity pinned entries may take up.
+    max_pinned_ratio: f64,
+    /// Maximum total weight of the pinned entries.
+    max_pinned_weight: u64,
+    /// Total weight of the pinned entries.
+    pinned_weight: u64,
+    /// Refetch cost worth one extra lap in the main queue.
+    cost_unit: u64,
+    /// Total weight of the entries in the small queue.
+    small_weight: u64,
+    /// Total weight of the entries in the main queue.
+    main_weight: u64,
+    /// Number of stale indices in the small queue.
+    small_stale: usize,
+    /// Number of stale indices in the main queue.
+    main_stale: usize,
+    /// Time to live given to entries inserted without an explicit one.
+    default_ttl: Option<Duration>,
+    /// Source of time for entry expiry.
+    clock: Arc<dyn Clock>,
+    /// Listener notified of entries leaving the cache.
+    listener: Option<Arc<dyn EvictionListener<K, V>>>,
+    /// Counters of cache activity.
+    counters: Counters,
+    /// Estimator of the miss-ratio curve, if enabled.
+    mrc: Option<MrcEstimator>,
 }
 
 impl<K, V> Cache<K, V>
-    where
-        K: PartialEq + Eq + Hash + Clone + Debug,
-        V: Clone + Debug,
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
 {
-    /// Creates a new cache with the given maximum size.
+    /// Creates a new cache with the given maximum number of entries.
     pub fn new(max_cache_size: NonZeroUsize) -> Self {
-        let max_small_size = max(max_cache_size.get() / 10, 1);
-        let max_main_size = max(max_cache_size.get() - max_small_size, 1);
+        Self::with_config(max_cache_size, S3FifoConfig::default())
+    }
+
+    /// Creates a new cache with the given maximum number of entries and S3-FIFO parameters.
+    pub fn with_config(max_cache_size: NonZeroUsize, config: S3FifoConfig) -> Self {
+        Self::with_weigher_and_config(max_cache_size.get() as u64, UnitWeigher, config)
+    }
+}
+
+impl<K, V, W> Cache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    W: Weigher<K, V>,
+{
+    /// Creates a new cache holding entries up to the given total weight, as computed by `weigher`.
+    pub fn with_weigher(max_weight: u64, weigher: W) -> Self {
+        Self::with_weigher_and_config(max_weight, weigher, S3FifoConfig::default())
+    }
+
+    /// Creates a new cache holding entries up to the given total weight, as computed by `weigher`,
+    /// with the given S3-FIFO parameters.
+    pub fn with_weigher_and_config(max_weight: u64, weigher: W, config: S3FifoConfig) -> Self {
+        let max_weight = max(max_weight, 1);
+        let max_small_weight = Self::small_budget(max_weight, config.small_ratio);
+        let max_ghost_weight = (max_weight as f64 * config.ghost_ratio) as u64;
 
         Self {
-            small: HeapRb::new(max_small_size),
-            main: HeapRb::new(max_main_size),
-            ghost: GhostQueue::new(max_main_size),
-            entries: HashMap::new(),
+            small: VecDeque::new(),
+            main: VecDeque::new(),
+            ghost: GhostQueue::new(max_ghost_weight),
+            slots: Vec::new(),
+            free: Vec::new(),
+            index: HashTable::new(),
+            hash_builder: DefaultHashBuilder::default(),
+            weigher,
+            max_weight,
+            max_small_weight,
+            small_ratio: config.small_ratio,
+            ghost_ratio: config.ghost_ratio,
+            max_freq: config.max_freq,
+            promotion_threshold: config.promotion_threshold,
+            tuner: config
+                .adaptive
+                .then(|| SmallQueueTuner::new(max_weight, max_small_weight)),
+            eviction_budget: config.eviction_budget,
+            sketch: config
+                .admission_filter
+                .then(|| FrequencySketch::new(max_weight)),
+            max_pinned_ratio: config.max_pinned_ratio,
+            max_pinned_weight: (max_weight as f64 * config.max_pinned_ratio) as u64,
+            pinned_weight: 0,
+            cost_unit: config.cost_unit,
+            small_weight: 0,
+            main_weight: 0,
+            small_stale: 0,
+            main_stale: 0,
+            default_ttl: None,
+            clock: Arc::new(SystemClock),
+            listener: None,
+            counters: Counters::default(),
+            mrc: None,
         }
     }
 
+    /// Sets the time to live given to entries inserted without an explicit one.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the clock used to expire entries.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the listener notified of every entry leaving the cache.
+    pub fn with_eviction_listener(mut self, listener: Arc<dyn EvictionListener<K, V>>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
     /// Returns a reference to the value of the given key if it exists in the cache.
-    pub fn get(&mut self, key: &K) -> Option<&V> {
-        if let Some(entry) = self.entries.get(key) {
-            let freq = entry.freq.load(Acquire);
-            if freq < MAX_FREQUENCY_LIMIT {
-                entry.freq.store(freq + 1, Release);
+    /// The access frequency is tracked atomically, so lookups only need shared access.
+    /// Expired entries are treated as misses.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(entry) = self.live_entry(key) {
+            // Concurrent readers may race on the counter, so bump it with a CAS loop
+            // to avoid losing increments or exceeding the limit.
+            let _ = entry.freq.fetch_update(Release, Acquire, |freq| {
+                (freq < self.max_freq).then_some(freq + 1)
+            });
+            if let Some(sketch) = &self.sketch {
+                sketch.increment(self.hash_builder.hash_one(key));
             }
+            if let Some(mrc) = &self.mrc {
+                mrc.access(self.hash_builder.hash_one(key), Some(entry.weight));
+            }
+            Counters::bump(&self.counters.hits);
+            self.counters.bytes_saved.fetch_add(entry.cost, Relaxed);
             Some(&entry.value)
         } else {
+            if let Some(mrc) = &self.mrc {
+                mrc.access(self.hash_builder.hash_one(key), None);
+            }
+            Counters::bump(&self.counters.misses);
             None
         }
     }
 
-    /// Inserts a new entry with the given key and value into the cache.
-    pub fn insert(&mut self, key: K, value: V) -> bool {
-        if self.entries.contains_key(&key) {
-            return false;
-        }
-        if self.ghost.contains(&key) {
-            self.insert_m(key.clone());
-        } else {
-            self.insert_s(key.clone());
-        }
-        let entry = Entry::new(key.clone(), value);
-        self.entries.insert(key, entry);
-        true
+    /// Returns a reference to the value of the given key if it exists in the cache, without
+    /// counting as an access: neither its frequency nor the statistics are updated.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.live_entry(key).map(|entry| &entry.value)
     }
 
-    /// Inserts a new entry into the small queue, evicting objects while full.
-    fn insert_s(&mut self, key: K) {
-        if let Some(victim) = self.small.push_overwrite(key.clone()) {
-            match self.entries.get(&victim).unwrap().freq.load(Relaxed) {
-                0 => {
-                    self.entries.remove(&victim);
-                    self.insert_g(victim);
-                }
-                _ => {
-                    let entry = self.entries.get(&victim).unwrap();
-                    entry.freq.store(0, Relaxed);
-                    self.insert_m(victim);
-                }
-            }
-        }
+    /// Returns true if the given key is present and not expired, without counting as an access.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.live_entry(key).is_some()
     }
 
-    /// Inserts a new entry into the main queue,
-    /// evicting and reinserting objects until a zero referenced entry is found.
-    fn insert_m(&mut self, key: K) {
-        if let Some(victim) = self.main.push_overwrite(key) {
-            if let Some(entry) = self.entries.get(&victim) {
-                match entry.freq.load(Relaxed) {
-                    0 => {
-                        self.entries.remove(&victim);
-                    }
-                    _ => {
-                        self.insert_m({
-                            self.entries.get(&victim).unwrap().freq.fetch_sub(1, Relaxed);
-                            victim
-                        });
-                    }
-                }
-            }
-        }
+    /// Returns the number of entries in the cache, including expired entries which were not
+    /// dropped yet.
+    pub fn len(&self) -> usize {
+        self.index.len()
     }
 
-    /// Inserts an entry into the ghost queue
-    fn insert_g(&mut self, key: K) {
-        self.ghost.push(key);
+    /// Returns true if the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.len() == 0
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fmt::Debug;
-    use std::sync::{Arc, Mutex};
-    use std::sync::atomic::AtomicUsize;
-    use std::sync::atomic::Ordering::Relaxed;
-    use std::thread;
-    use rand::{Rng, thread_rng};
+    /// Returns the maximum total weight of the entries in the cache, which is the maximum number
+    /// of entries with unit weights.
+    pub fn capacity(&self) -> u64 {
+        self.max_weight
+    }
 
-    use super::*;
+    /// Returns the live entries of the small queue with their frequency, from the next entry to
+    /// be evicted to the most recently inserted one.
+    pub fn iter_small(&self) -> impl Iterator<Item = (&K, &V, u8)> + '_ {
+        self.iter_queue(&self.small)
+    }
 
-    fn assert_opt_eq<V: PartialEq + Debug>(opt: Option<&V>, v: V) {
-        assert!(opt.is_some());
-        assert_eq!(opt.unwrap(), &v);
+    /// Returns the live entries of the main queue with their frequency, from the next entry to be
+    /// evicted to the most recently inserted or reinserted one.
+    pub fn iter_main(&self) -> impl Iterator<Item = (&K, &V, u8)> + '_ {
+        self.iter_queue(&self.main)
     }
 
-    #[test]
-    fn test_push_and_read() {
-        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+    /// Returns the live entries of the given queue in queue order.
+    fn iter_queue<'a>(
+        &'a self,
+        queue: &'a VecDeque<u32>,
+    ) -> impl Iterator<Item = (&'a K, &'a V, u8)> + 'a {
+        let now = self.clock.now();
+        queue
+            .iter()
+            .filter_map(|&slot| self.slots[slot as usize].as_ref())
+            .filter(move |entry| !entry.is_expired(now))
+            .map(|entry| (&entry.key, &entry.value, entry.freq.load(Relaxed)))
+    }
 
-        cache.insert("apple", "red");
-        assert_opt_eq(cache.get(&"apple"), "red");
-        cache.insert("banana", "yellow");
-        assert_opt_eq(cache.get(&"apple"), "red");
-        assert_opt_eq(cache.get(&"banana"), "yellow");
+    /// Removes every entry from the cache and forgets the keys of the ghost queue. The listener,
+    /// if any, is notified of every removed entry. The statistics are kept.
+    pub fn clear(&mut self) {
+        let slots = mem::take(&mut self.slots);
+        self.small.clear();
+        self.main.clear();
+        self.free.clear();
+        self.index.clear();
+        self.ghost.clear();
+        self.small_weight = 0;
+        self.main_weight = 0;
+        self.small_stale = 0;
+        self.main_stale = 0;
+        self.pinned_weight = 0;
+        for entry in slots.into_iter().flatten() {
+            self.notify(&entry.key, &entry.value, EvictionCause::Removed);
+        }
     }
 
-    #[test]
-    fn test_push_removes_oldest() {
-        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+    /// Returns a clone of the value of the given key, computing it with `f` and inserting it on a
+    /// miss. The computed value is returned even if it is too heavy to be cached.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
 
-        let fruits = vec![
-            ("apple", "red"),
-            ("banana", "yellow"),
-            ("orange", "orange"),
-            ("pear", "green"),
-            ("peach", "pink"),
-        ];
+    /// Returns a clone of the value of the given key, computing it with `f` and inserting it on a
+    /// miss. If `f` fails, its error is returned and nothing is cached.
+    pub fn try_get_or_insert_with<F, E>(&mut self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = f()?;
+        self.insert(key, value.clone());
+        Ok(value)
+    }
 
-        for (fruit, color) in fruits {
-            cache.insert(fruit, color);
+    /// Returns a snapshot of the statistics of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let counters = &self.counters;
+        CacheStats {
+            hits: counters.hits.load(Relaxed),
+            misses: counters.misses.load(Relaxed),
+            bytes_saved: counters.bytes_saved.load(Relaxed),
+            insertions: counters.insertions.load(Relaxed),
+            promotions: counters.promotions.load(Relaxed),
+            ghost_hits: counters.ghost_hits.load(Relaxed),
+            main_reinsertions: counters.main_reinsertions.load(Relaxed),
+            small_evictions: counters.small_evictions.load(Relaxed),
+            main_evictions: counters.main_evictions.load(Relaxed),
+            expirations: counters.expirations.load(Relaxed),
+            forced_evictions: counters.forced_evictions.load(Relaxed),
+            admitted: counters.admitted.load(Relaxed),
+            rejected: counters.rejected.load(Relaxed),
+            small_len: (self.small.len() - self.small_stale) as u64,
+            main_len: (self.main.len() - self.main_stale) as u64,
+            ghost_len: self.ghost.len() as u64,
+            small_weight: self.small_weight,
+            main_weight: self.main_weight,
+            small_capacity: self.max_small_weight,
+            pinned_weight: self.pinned_weight,
+            mrc: self.mrc.as_ref().map(|mrc| mrc.curve(self.max_weight)),
         }
+    }
 
-        assert!(cache.get(&"apple").is_none());
-        assert_opt_eq(cache.get(&"peach"), "pink");
+    /// Starts estimating the miss-ratio curve of the cache from its lookups, following at most
+    /// `max_samples` keys, and drops any previous estimate. Lookups cost more while the estimation
+    /// is enabled, mostly for the sampled keys.
+    pub fn enable_mrc(&mut self, max_samples: usize) {
+        self.mrc = Some(MrcEstimator::new(max_samples));
+    }
 
-        // "apple" should have been removed from the cache.
-        cache.insert("apple", "red");
-        cache.get(&"apple");
-        cache.insert("banana", "yellow");
+    /// Stops estimating the miss-ratio curve of the cache and drops the estimate.
+    pub fn disable_mrc(&mut self) {
+        self.mrc = None;
+    }
 
-        // assert!(cache.get(&"pear").is_none());
-        assert_opt_eq(cache.get(&"apple"), "red");
-        assert_opt_eq(cache.get(&"banana"), "yellow");
+    /// Inserts a new entry with the given key and value into the cache, expiring after the default
+    /// time to live if one is set.
+    /// Returns false if the key is already present, the entry is heavier than the room left by the
+    /// pinned entries, or the admission filter rejects it.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.insert_with_expiry(key, value, self.default_ttl, 0)
     }
 
-    #[test]
-    fn test_concurrent() {
-        let cache = Arc::new(Mutex::new(Cache::new(NonZeroUsize::new(2).unwrap())));
-        for i in 0..1000 {
-            let i_cache = Arc::clone(&cache);
-            thread::spawn(move || i_cache.lock().unwrap().insert(i, i));
-            let g_cache = Arc::clone(&cache);
-            thread::spawn(move || { g_cache.lock().unwrap().get(&i); });
-        };
+    /// Inserts a new entry with the given key and value into the cache, along with the cost of
+    /// refetching it on a miss, expiring after the default time to live if one is set.
+    /// Returns false in the same cases as `insert`.
+    pub fn insert_with_cost(&mut self, key: K, value: V, cost: u64) -> bool {
+        self.insert_with_expiry(key, value, self.default_ttl, cost)
     }
 
-    #[test]
-    fn test_rng_criterion() {
-        let mut rng = thread_rng();
-        let nums: Vec<u64> =
-            (0..(100000 * 2))
-                .map(|i| {
-                    if i % 2 == 0 {
-                        rng.gen::<u64>() % 16384
-                    } else {
-                        rng.gen::<u64>() % 32768
-                    }
-                })
+    /// Inserts a new entry with the given key and value into the cache, expiring after `ttl`.
+    /// Returns false if the key is already present, the entry is heavier than the room left by the
+    /// pinned entries, or the admission filter rejects it.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> bool {
+        self.insert_with_expiry(key, value, Some(ttl), 0)
+    }
+
+    /// Inserts the given key and value into the cache, or updates the value if the key is already
+    /// present. An updated entry keeps its queue, frequency and cost, and its expiry is reset using
+    /// the default time to live. Returns the previous value, if any.
+    /// If the new value is heavier than the cache, the key is removed instead. A pinned entry
+    /// whose new value takes the pinned entries over their limit is unpinned.
+    pub fn insert_or_update(&mut self, key: K, value: V) -> Option<V> {
+        let weight = max(self.weigher.weight(&key, &value), 1);
+        if !self.contains_key(&key) {
+            self.drop_expired(&key);
+            self.insert_weighted(key, value, weight, self.default_ttl, false, 0);
+            return None;
+        }
+        if weight > self.max_weight {
+            return self.remove(&key);
+        }
+
+        let expires_at = self.expires_at(self.default_ttl);
+        let entry = self.entry_mut(&key).unwrap();
+        entry.expires_at = expires_at;
+        let previous = mem::replace(&mut entry.value, value);
+        let previous_weight = mem::replace(&mut entry.weight, weight);
+        let pinned = entry.pinned;
+        match entry.queue {
+            Queue::Small => self.small_weight = self.small_weight - previous_weight + weight,
+            Queue::Main => self.main_weight = self.main_weight - previous_weight + weight,
+        }
+        if pinned {
+            self.pinned_weight = self.pinned_weight - previous_weight + weight;
+            if self.pinned_weight > self.max_pinned_weight {
+                self.unpin(&key);
+            }
+        }
+
+        self.notify(&key, &previous, EvictionCause::Replaced);
+
+        while self.small_weight + self.main_weight > self.max_weight && self.evict() {}
+        Some(previous)
+    }
+
+    /// Replaces the value of the given key if it is present in the cache, keeping its queue and
+    /// frequency. Returns the previous value, or `None` without caching anything if the key is
+    /// absent.
+    pub fn replace(&mut self, key: K, value: V) -> Option<V> {
+        if !self.contains_key(&key) {
+            return None;
+        }
+        self.insert_or_update(key, value)
+    }
+
+    /// Inserts a new pinned entry with the given key and value into the cache, expiring after the
+    /// default time to live if one is set. Pinned entries skip the admission filter.
+    /// If the key is already present, its entry is pinned instead and `Ok(false)` is returned.
+    /// Fails if pinning the entry would take the pinned entries over their limit.
+    pub fn insert_pinned(&mut self, key: K, value: V) -> Result<bool, PinError> {
+        if self.contains_key(&key) {
+            self.pin(&key)?;
+            return Ok(false);
+        }
+        let weight = max(self.weigher.weight(&key, &value), 1);
+        if self.pinned_weight + weight > self.max_pinned_weight {
+            return Err(PinError::LimitExceeded);
+        }
+        self.drop_expired(&key);
+        Ok(self.insert_weighted(key, value, weight, self.default_ttl, true, 0))
+    }
+
+    /// Pins the entry of the given key, so that it is never evicted. Pinning an entry which is
+    /// already pinned does nothing.
+    /// Fails if the key is absent or expired, or if pinning the entry would take the pinned
+    /// entries over their limit.
+    pub fn pin<Q>(&mut self, key: &Q) -> Result<(), PinError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.find(key).ok_or(PinError::NotFound)?;
+        let entry = self.slots[slot as usize].as_ref().unwrap();
+        if self.expired(entry) {
+            return Err(PinError::NotFound);
+        }
+        if entry.pinned {
+            return Ok(());
+        }
+        if self.pinned_weight + entry.weight > self.max_pinned_weight {
+            return Err(PinError::LimitExceeded);
+        }
+        self.pinned_weight += entry.weight;
+        self.slots[slot as usize].as_mut().unwrap().pinned = true;
+        Ok(())
+    }
+
+    /// Unpins the entry of the given key, which is evicted by the regular S3-FIFO rules from then
+    /// on. Returns false if the key is absent or was not pinned.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(slot) = self.find(key) else {
+            return false;
+        };
+        let entry = self.slots[slot as usize].as_mut().unwrap();
+        if !entry.pinned {
+            return false;
+        }
+        entry.pinned = false;
+        self.pinned_weight -= entry.weight;
+        true
+    }
+
+    /// Returns true if the given key is present and pinned.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entry(key).is_some_and(|entry| entry.pinned)
+    }
+
+    /// Removes the given key from the cache, returning its value if it was present and not expired.
+    /// Removed keys are not remembered by the ghost queue.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.find(key)?;
+        self.remove_slot(slot)
+    }
+
+    /// Removes the entry in the given slot, returning its value if it was not expired.
+    fn remove_slot(&mut self, slot: u32) -> Option<V> {
+        let entry = self.unlink(slot);
+        if self.expired(&entry) {
+            self.notify(&entry.key, &entry.value, EvictionCause::Expired);
+            return None;
+        }
+        self.notify(&entry.key, &entry.value, EvictionCause::Removed);
+        Some(entry.value)
+    }
+
+    /// Removes the entry in the given slot from the cache and its queue accounting, leaving a stale
+    /// index behind in its queue.
+    fn unlink(&mut self, slot: u32) -> Entry<K, V> {
+        let entry = self.take(slot);
+        match entry.queue {
+            Queue::Small => {
+                self.small_weight -= entry.weight;
+                self.small_stale += 1;
+                if self.small_stale > self.small.len() / 2 {
+                    Self::compact(&mut self.small, &self.slots, &mut self.free);
+                    self.small_stale = 0;
+                }
+            }
+            Queue::Main => {
+                self.main_weight -= entry.weight;
+                self.main_stale += 1;
+                if self.main_stale > self.main.len() / 2 {
+                    Self::compact(&mut self.main, &self.slots, &mut self.free);
+                    self.main_stale = 0;
+                }
+            }
+        }
+        entry
+    }
+
+    /// Removes every entry for which the predicate returns true.
+    /// Returns the number of removed live entries: matching entries which had already expired are
+    /// dropped as well, but not counted.
+    pub fn invalidate_if<F>(&mut self, mut predicate: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let slots: Vec<u32> = self
+            .slots
+            .iter()
+            .zip(0..)
+            .filter(|(entry, _)| {
+                entry
+                    .as_ref()
+                    .is_some_and(|entry| predicate(&entry.key, &entry.value))
+            })
+            .map(|(_, slot)| slot)
+            .collect();
+        slots
+            .into_iter()
+            .filter_map(|slot| self.remove_slot(slot))
+            .count()
+    }
+
+    /// Returns the total weight of the entries in the cache.
+    pub fn weight(&self) -> u64 {
+        self.small_weight + self.main_weight
+    }
+
+    /// Changes the maximum total weight of the entries in the cache.
+    ///
+    /// The budget of the small queue and the capacity of the ghost queue are scaled in proportion;
+    /// in adaptive mode, the small queue keeps its current share of the capacity. The frequency
+    /// sketch of the admission filter, if any, is resized and starts over. Shrinking evicts
+    /// entries through the regular S3-FIFO rules until the cache fits, while growing keeps every
+    /// entry. Pinned entries are kept even if they no longer fit within their share of the new
+    /// capacity, which only prevents pinning more entries until enough of them are unpinned.
+    pub fn resize(&mut self, max_weight: u64) {
+        let max_weight = max(max_weight, 1);
+        let small_ratio = match self.tuner {
+            Some(_) => self.max_small_weight as f64 / self.max_weight as f64,
+            None => self.small_ratio,
+        };
+        self.max_weight = max_weight;
+        self.max_small_weight = Self::small_budget(max_weight, small_ratio);
+        if self.tuner.is_some() {
+            self.tuner = Some(SmallQueueTuner::new(max_weight, self.max_small_weight));
+        }
+        self.ghost
+            .resize((max_weight as f64 * self.ghost_ratio) as u64);
+        if self.sketch.is_some() {
+            self.sketch = Some(FrequencySketch::new(max_weight));
+        }
+        self.max_pinned_weight = (max_weight as f64 * self.max_pinned_ratio) as u64;
+
+        self.shrink_to(max_weight);
+    }
+
+    /// Evicts entries through the regular S3-FIFO rules until the total weight of the entries is
+    /// at most `weight`, keeping the capacity unchanged.
+    /// Returns false if pinned entries prevent the cache from shrinking that far.
+    pub fn shrink_to(&mut self, weight: u64) -> bool {
+        while self.small_weight + self.main_weight > weight {
+            if !self.evict() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the state persisted in warm-start files: the resident entries of both queues in
+    /// queue order with their frequency and cost, and the ghost queue. Entries with a time to live
+    /// are left out, as their expiry cannot be carried over to another process.
+    pub(crate) fn warm_state(&self) -> WarmState<K, V> {
+        let resident = |queue: &VecDeque<u32>| {
+            queue
+                .iter()
+                .filter_map(|&slot| self.slots[slot as usize].as_ref())
+                .filter(|entry| entry.expires_at.is_none())
+                .map(|entry| {
+                    let freq = entry.freq.load(Relaxed);
+                    (entry.key.clone(), entry.value.clone(), freq, entry.cost)
+                })
                 .collect()
-            ;
-        let mut l = Cache::new(NonZeroUsize::new(8192).unwrap());
-        (0..99999).for_each(|v| {
-            let k = nums[v];
-            l.insert(k, k);
+        };
+        WarmState {
+            small: resident(&self.small),
+            main: resident(&self.main),
+            ghost: self.ghost.iter().collect(),
+        }
+    }
+
+    /// Restores the given warm-start state on top of the current contents of the cache, keeping
+    /// the queue, order, frequency and cost of every entry, whose cost credits start over. Entries
+    /// of the main queue are restored first, and entries which are already present or do not fit in
+    /// the remaining capacity are skipped. Returns the number of restored entries.
+    pub(crate) fn restore(&mut self, state: WarmState<K, V>) -> usize {
+        let mut restored = 0;
+        for (queue, entries) in [(Queue::Main, state.main), (Queue::Small, state.small)] {
+            for (key, value, freq, cost) in entries {
+                let weight = max(self.weigher.weight(&key, &value), 1);
+                if self.contains_key(&key) || self.weight() + weight > self.max_weight {
+                    continue;
+                }
+                let mut entry = Entry::new(key, value, weight, queue, None);
+                entry.freq.store(freq.min(self.max_freq), Relaxed);
+                entry.cost = cost;
+                entry.credits = self.cost_credits(cost);
+                let slot = self.allocate(entry);
+                match queue {
+                    Queue::Small => self.insert_s(slot, weight),
+                    Queue::Main => self.insert_m(slot, weight),
+                }
+                restored += 1;
+            }
+        }
+        for (fingerprint, weight) in state.ghost {
+            self.ghost.push_fingerprint(fingerprint, weight);
+        }
+        restored
+    }
+
+    /// Returns the number of extra laps in the main queue earned by the given refetch cost.
+    fn cost_credits(&self, cost: u64) -> u8 {
+        let credits = (cost / self.cost_unit).saturating_add(1).ilog2();
+        credits.min(self.max_freq as u32) as u8
+    }
+
+    /// Returns the weight budget of the small queue for the given capacity and share.
+    fn small_budget(max_weight: u64, small_ratio: f64) -> u64 {
+        ((max_weight as f64 * small_ratio) as u64).clamp(1, max_weight)
+    }
+
+    /// Notifies the eviction listener, if any, of an entry leaving the cache.
+    fn notify(&self, key: &K, value: &V, cause: EvictionCause) {
+        match cause {
+            EvictionCause::SmallEvicted => Counters::bump(&self.counters.small_evictions),
+            EvictionCause::MainEvicted => Counters::bump(&self.counters.main_evictions),
+            EvictionCause::Expired => Counters::bump(&self.counters.expirations),
+            EvictionCause::Removed | EvictionCause::Replaced => {}
+        }
+        if let Some(listener) = &self.listener {
+            listener.on_evict(key, value, cause);
+        }
+    }
+
+    /// Removes the entry of the given key, which is known not to be live, if it is still present.
+    fn drop_expired(&mut self, key: &K) {
+        if let Some(slot) = self.find(key) {
+            let entry = self.unlink(slot);
+            self.notify(&entry.key, &entry.value, EvictionCause::Expired);
+        }
+    }
+
+    /// Removes an entry whose index was taken off the head of its queue, frees its slot and
+    /// notifies the listener.
+    fn evict_entry(&mut self, slot: u32, cause: EvictionCause) {
+        let entry = self.take(slot);
+        self.free.push(slot);
+        self.notify(&entry.key, &entry.value, cause);
+    }
+
+    /// Returns the slot of the entry of the given key, if any.
+    fn find<Q>(&self, key: &Q) -> Option<u32>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        self.index
+            .find(hash, |&slot| {
+                self.slots[slot as usize]
+                    .as_ref()
+                    .is_some_and(|entry| entry.key.borrow() == key)
+            })
+            .copied()
+    }
+
+    /// Returns the entry of the given key, if any.
+    fn entry<Q>(&self, key: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key)
+            .and_then(|slot| self.slots[slot as usize].as_ref())
+    }
+
+    /// Returns the entry of the given key if it is not expired.
+    fn live_entry<Q>(&self, key: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entry(key).filter(|entry| !self.expired(entry))
+    }
+
+    /// Returns the entry of the given key mutably, if any.
+    fn entry_mut(&mut self, key: &K) -> Option<&mut Entry<K, V>> {
+        self.find(key)
+            .and_then(|slot| self.slots[slot as usize].as_mut())
+    }
+
+    /// Stores the given entry in a free slot, growing the arena if there is none, and indexes it.
+    fn allocate(&mut self, entry: Entry<K, V>) -> u32 {
+        let hash = self.hash_builder.hash_one(&entry.key);
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot as usize] = Some(entry);
+                slot
+            }
+            None => {
+                let slot = u32::try_from(self.slots.len()).expect("too many entries in the cache");
+                self.slots.push(Some(entry));
+                slot
+            }
+        };
+        let (slots, hash_builder) = (&self.slots, &self.hash_builder);
+        self.index.insert_unique(hash, slot, |&slot| {
+            let entry = slots[slot as usize].as_ref().unwrap();
+            hash_builder.hash_one(&entry.key)
         });
+        slot
     }
 
-    #[test]
-    fn test_no_memory_leaks() {
-        static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
+    /// Takes the entry out of the given slot and the index, leaving the slot empty.
+    fn take(&mut self, slot: u32) -> Entry<K, V> {
+        let entry = self.slots[slot as usize].take().unwrap();
+        if entry.pinned {
+            self.pinned_weight -= entry.weight;
+        }
+        let hash = self.hash_builder.hash_one(&entry.key);
+        if let Ok(indexed) = self.index.find_entry(hash, |&other| other == slot) {
+            indexed.remove();
+        }
+        entry
+    }
 
-        #[derive(Debug, Clone)]
-        struct DropCounter;
+    /// Returns true if the given entry is expired.
+    fn expired(&self, entry: &Entry<K, V>) -> bool {
+        entry.expires_at.is_some() && entry.is_expired(self.clock.now())
+    }
 
-        impl Drop for DropCounter {
-            fn drop(&mut self) {
-                DROP_COUNT.fetch_add(1, Relaxed);
+    /// Returns the expiry instant for an entry inserted now with the given time to live.
+    fn expires_at(&self, ttl: Option<Duration>) -> Option<Instant> {
+        ttl.map(|ttl| self.clock.now() + ttl)
+    }
+
+    /// Inserts a new entry with the given refetch cost unless a live entry with the same key
+    /// exists. An expired entry with the same key is replaced.
+    fn insert_with_expiry(&mut self, key: K, value: V, ttl: Option<Duration>, cost: u64) -> bool {
+        if self.contains_key(&key) {
+            return false;
+        }
+        self.drop_expired(&key);
+        let weight = max(self.weigher.weight(&key, &value), 1);
+        self.insert_weighted(key, value, weight, ttl, false, cost)
+    }
+
+    /// Inserts a new entry of the given weight, evicting other entries until it fits.
+    fn insert_weighted(
+        &mut self,
+        key: K,
+        value: V,
+        weight: u64,
+        ttl: Option<Duration>,
+        pinned: bool,
+        cost: u64,
+    ) -> bool {
+        // Pinned entries are never evicted, so only the rest of the capacity can make room.
+        if weight > self.max_weight.saturating_sub(self.pinned_weight) {
+            return false;
+        }
+        if !pinned && !self.admit(&key, weight) {
+            Counters::bump(&self.counters.rejected);
+            return false;
+        }
+
+        while self.small_weight + self.main_weight + weight > self.max_weight {
+            if !self.evict() {
+                return false;
             }
         }
 
-        let n = 100;
-        for _ in 0..n {
-            let mut cache = Cache::new(NonZeroUsize::new(20).unwrap());
-            for i in 0..n {
-                cache.insert(i, DropCounter {});
+        let ghost_hit = self.ghost.contains(&key);
+        if ghost_hit {
+            Counters::bump(&self.counters.ghost_hits);
+            if let Some(tuner) = &mut self.tuner {
+                tuner.record_ghost_hit();
             }
         }
-        assert_eq!(DROP_COUNT.load(Relaxed), n * n);
+        Counters::bump(&self.counters.insertions);
+        let queue = if ghost_hit || weight > self.max_small_weight {
+            Queue::Main
+        } else {
+            Queue::Small
+        };
+        if let Some(mrc) = &self.mrc {
+            mrc.set_weight(self.hash_builder.hash_one(&key), weight);
+        }
+        let expires_at = self.expires_at(ttl);
+        let mut entry = Entry::new(key, value, weight, queue, expires_at);
+        entry.cost = cost;
+        entry.credits = self.cost_credits(cost);
+        if pinned {
+            entry.pinned = true;
+            self.pinned_weight += weight;
+        }
+        let slot = self.allocate(entry);
+        match queue {
+            Queue::Small => self.insert_s(slot, weight),
+            Queue::Main => self.insert_m(slot, weight),
+        }
+        true
+    }
+
+    /// Drops the stale indices of the given queue and frees their slots.
+    fn compact(queue: &mut VecDeque<u32>, slots: &[Option<Entry<K, V>>], free: &mut Vec<u32>) {
+        queue.retain(|&slot| {
+            let live = slots[slot as usize].is_some();
+            if !live {
+                free.push(slot);
+            }
+            live
+        });
+    }
+
+    /// Records the insertion of a new key in the admission filter, if enabled, and returns whether
+    /// it should be admitted. A key is admitted if the cache has room for it or it comes back from
+    /// the ghost queue, and otherwise only if it was accessed more often than the entry the next
+    /// eviction would look at first.
+    fn admit(&self, key: &K, weight: u64) -> bool {
+        let Some(sketch) = &self.sketch else {
+            return true;
+        };
+        let hash = self.hash_builder.hash_one(key);
+        sketch.increment(hash);
+        if self.small_weight + self.main_weight + weight <= self.max_weight
+            || self.ghost.contains(key)
+        {
+            return true;
+        }
+
+        let queue = if self.small_weight >= self.max_small_weight || self.main.is_empty() {
+            &self.small
+        } else {
+            &self.main
+        };
+        let victim = queue.iter().find_map(|&slot| {
+            self.slots[slot as usize]
+                .as_ref()
+                .filter(|entry| !entry.pinned)
+        });
+        let admitted = victim.map_or(true, |victim| {
+            sketch.estimate(hash) > sketch.estimate(self.hash_builder.hash_one(&victim.key))
+        });
+        if admitted {
+            Counters::bump(&self.counters.admitted);
+        }
+        admitted
+    }
+
+    /// Evicts entries until at least one entry has left the cache. Entries are taken from the
+    /// small queue while it is over its budget, and from the main queue otherwise, falling back to
+    /// the small queue when every entry of the main queue is pinned.
+    /// Returns false if nothing could be evicted, as every entry is pinned.
+    fn evict(&mut self) -> bool {
+        let now = self.clock.now();
+        let small_over_budget = self.small_weight >= self.max_small_weight;
+        let mut budget = self.eviction_budget;
+        let evicted = (small_over_budget || self.main.is_empty()) && self.evict_s(now, &mut budget);
+        self.tune_small_queue();
+        evicted || self.evict_m(now, budget) || self.evict_s(now, &mut budget)
+    }
+
+    /// Moves the budget of the small queue in adaptive mode, once per window.
+    fn tune_small_queue(&mut self) {
+        if let Some(budget) = self
+            .tuner
+            .as_mut()
+            .and_then(|tuner| tuner.next_budget(self.max_small_weight))
+        {
+            self.max_small_weight = budget;
+        }
+    }
+
+    /// Inserts a new entry into the small queue.
+    fn insert_s(&mut self, slot: u32, weight: u64) {
+        self.small.push_back(slot);
+        self.small_weight += weight;
+    }
+
+    /// Inserts a new entry into the main queue.
+    fn insert_m(&mut self, slot: u32, weight: u64) {
+        self.main.push_back(slot);
+        self.main_weight += weight;
+    }
+
+    /// Evicts from the small queue, moving entries accessed often enough into the main queue,
+    /// until an entry below the promotion threshold is evicted into the ghost queue. Every
+    /// promotion spends one unit of the budget, and once it is spent the head is evicted into the
+    /// ghost queue whatever its frequency.
+    /// Expired entries are dropped without being promoted or remembered.
+    /// Returns false if the small queue ran empty without evicting anything.
+    fn evict_s(&mut self, now: Instant, budget: &mut u32) -> bool {
+        while let Some(victim) = self.small.pop_front() {
+            let Some(entry) = self.slots[victim as usize].as_mut() else {
+                self.small_stale -= 1;
+                self.free.push(victim);
+                continue;
+            };
+            let weight = entry.weight;
+            self.small_weight -= weight;
+            if entry.is_expired(now) {
+                self.evict_entry(victim, EvictionCause::Expired);
+                return true;
+            }
+            if entry.pinned {
+                entry.queue = Queue::Main;
+                self.insert_m(victim, weight);
+                continue;
+            }
+            let hot = entry.freq.load(Relaxed) >= self.promotion_threshold;
+            let promoted = hot && *budget > 0;
+            if let Some(tuner) = &mut self.tuner {
+                tuner.record_departure(weight, promoted);
+            }
+            if !promoted {
+                if hot {
+                    Counters::bump(&self.counters.forced_evictions);
+                }
+                self.ghost.push(&entry.key, weight);
+                self.evict_entry(victim, EvictionCause::SmallEvicted);
+                return true;
+            }
+            *budget -= 1;
+            entry.freq.store(0, Relaxed);
+            entry.queue = Queue::Main;
+            Counters::bump(&self.counters.promotions);
+            self.insert_m(victim, weight);
+        }
+        false
+    }
+
+    /// Evicts from the main queue, reinserting entries until a zero referenced or expired entry is
+    /// found or the budget of reinsertions is spent, in which case the head is evicted whatever its
+    /// frequency. An entry whose frequency ran out is reinserted while it has cost credits left,
+    /// spending one per lap. Pinned entries are moved to the tail without spending their frequency
+    /// or the budget.
+    /// Returns false if the main queue holds no entry which can be evicted.
+    fn evict_m(&mut self, now: Instant, mut budget: u32) -> bool {
+        // Number of pinned entries in a row moved to the tail, to stop once all of them were.
+        let mut pinned_in_a_row = 0;
+        while let Some(victim) = self.main.pop_front() {
+            let Some(entry) = self.slots[victim as usize].as_mut() else {
+                self.main_stale -= 1;
+                self.free.push(victim);
+                continue;
+            };
+            if entry.is_expired(now) {
+                self.main_weight -= entry.weight;
+                self.evict_entry(victim, EvictionCause::Expired);
+                return true;
+            }
+            if entry.pinned {
+                self.main.push_back(victim);
+                pinned_in_a_row += 1;
+                if pinned_in_a_row >= self.main.len() - self.main_stale {
+                    return false;
+                }
+                continue;
+            }
+            pinned_in_a_row = 0;
+            let freq = entry.freq.load(Relaxed);
+            if (freq == 0 && entry.credits == 0) || budget == 0 {
+                if freq > 0 || entry.credits > 0 {
+                    Counters::bump(&self.counters.forced_evictions);
+                }
+                self.main_weight -= entry.weight;
+                self.evict_entry(victim, EvictionCause::MainEvicted);
+                return true;
+            }
+            budget -= 1;
+            if freq > 0 {
+                entry.freq.fetch_sub(1, Relaxed);
+            } else {
+                entry.credits -= 1;
+            }
+            Counters::bump(&self.counters.main_reinsertions);
+            self.main.push_back(victim);
+        }
+        false
+    }
+}
+
+impl<K, V, W> Policy<K, V> for Cache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    W: Weigher<K, V>,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        Cache::get(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> bool {
+        Cache::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        Cache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        Cache::len(self)
+    }
+
+    fn stats(&self) -> CacheStats {
+        Cache::stats(self)
+    }
+}
+
+impl<K, V, W> Cache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug + Persist,
+    V: Clone + Debug + Persist,
+    W: Weigher<K, V>,
+{
+    /// Saves the resident entries with their queue and frequency, and the ghost queue, to a
+    /// warm-start file at the given path. The file can only be loaded back with the same `tag`,
+    /// which should identify the data the cached values were read from.
+    /// Entries with a time to live are not saved.
+    pub fn save_warm_start(&self, path: &Path, tag: u64) -> io::Result<()> {
+        self.warm_state().save(path, tag)
+    }
+
+    /// Loads a warm-start file saved with the same `tag` into the cache, and returns the number of
+    /// restored entries. Files with another format version or tag, or which fail their checksum,
+    /// are rejected with `InvalidData` without changing the cache.
+    pub fn load_warm_start(&mut self, path: &Path, tag: u64) -> io::Result<usize> {
+        let state = WarmState::load(path, tag)?;
+        Ok(self.restore(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+    use std::fmt::Debug;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+
+    fn assert_opt_eq<V: PartialEq + Debug>(opt: Option<&V>, v: V) {
+        assert!(opt.is_some());
+        assert_eq!(opt.unwrap(), &v);
+    }
+
+    #[test]
+    fn test_push_and_read() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+
+        cache.insert("apple", "red");
+        assert_opt_eq(cache.get(&"apple"), "red");
+        cache.insert("banana", "yellow");
+        assert_opt_eq(cache.get(&"apple"), "red");
+        assert_opt_eq(cache.get(&"banana"), "yellow");
+    }
+
+    #[test]
+    fn test_push_removes_oldest() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+
+        let fruits = vec![
+            ("apple", "red"),
+            ("banana", "yellow"),
+            ("orange", "orange"),
+            ("pear", "green"),
+            ("peach", "pink"),
+        ];
+
+        for (fruit, color) in fruits {
+            cache.insert(fruit, color);
+        }
+
+        assert!(cache.get(&"apple").is_none());
+        assert_opt_eq(cache.get(&"peach"), "pink");
+
+        // "apple" should have been removed from the cache.
+        cache.insert("apple", "red");
+        cache.get(&"apple");
+        cache.insert("banana", "yellow");
+
+        // assert!(cache.get(&"pear").is_none());
+        assert_opt_eq(cache.get(&"apple"), "red");
+        assert_opt_eq(cache.get(&"banana"), "yellow");
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let cache = Arc::new(Mutex::new(Cache::new(NonZeroUsize::new(2).unwrap())));
+        for i in 0..1000 {
+            let i_cache = Arc::clone(&cache);
+            thread::spawn(move || i_cache.lock().unwrap().insert(i, i));
+            let g_cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                g_cache.lock().unwrap().get(&i);
+            });
+        }
+    }
+
+    #[test]
+    fn test_rng_criterion() {
+        let mut rng = thread_rng();
+        let nums: Vec<u64> = (0..(100000 * 2))
+            .map(|i| {
+                if i % 2 == 0 {
+                    rng.gen::<u64>() % 16384
+                } else {
+                    rng.gen::<u64>() % 32768
+                }
+            })
+            .collect();
+        let mut l = Cache::new(NonZeroUsize::new(8192).unwrap());
+        (0..99999).for_each(|v| {
+            let k = nums[v];
+            l.insert(k, k);
+        });
+    }
+
+    #[test]
+    fn test_no_memory_leaks() {
+        static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Debug, Clone)]
+        struct DropCounter;
+
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROP_COUNT.fetch_add(1, Relaxed);
+            }
+        }
+
+        let n = 100;
+        for _ in 0..n {
+            let mut cache = Cache::new(NonZeroUsize::new(20).unwrap());
+            for i in 0..n {
+                cache.insert(i, DropCounter {});
+            }
+        }
+        assert_eq!(DROP_COUNT.load(Relaxed), n * n);
+    }
+
+    #[test]
+    fn test_slots_are_reused() {
+        let mut cache = Cache::new(NonZeroUsize::new(100).unwrap());
+        for i in 0..10_000 {
+            cache.insert(i, i);
+            if i % 2 == 0 {
+                cache.get(&i);
+            }
+            if i % 3 == 0 {
+                cache.remove(&i);
+            }
+        }
+        assert!(cache.index.len() <= 100);
+        // Every slot is either live, stale in a queue, or free.
+        assert_eq!(
+            cache.slots.len(),
+            cache.small.len() + cache.main.len() + cache.free.len()
+        );
+        assert!(cache.slots.len() <= 2 * 100 + 1);
+    }
+
+    #[derive(Clone, Copy)]
+    struct LenWeigher;
+
+    impl Weigher<u64, Vec<u8>> for LenWeigher {
+        fn weight(&self, _key: &u64, value: &Vec<u8>) -> u64 {
+            value.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_weighted_capacity() {
+        let mut cache = Cache::with_weigher(1000, LenWeigher);
+
+        for i in 0..100 {
+            assert!(cache.insert(i, vec![0; 50]));
+            assert!(cache.weight() <= 1000);
+        }
+        assert_eq!(cache.weight(), 1000);
+        assert_eq!(cache.index.len(), 20);
+    }
+
+    #[test]
+    fn test_weighted_queue_budgets() {
+        let mut cache = Cache::with_weigher(1000, LenWeigher);
+
+        // The small queue is given 10% of the capacity.
+        assert!(cache.insert(1, vec![0; 100]));
+        assert_eq!(cache.small_weight, 100);
+        assert_eq!(cache.main_weight, 0);
+
+        // Entries heavier than the small queue go straight into the main queue.
+        assert!(cache.insert(2, vec![0; 101]));
+        assert_eq!(cache.small_weight, 100);
+        assert_eq!(cache.main_weight, 101);
+
+        // Entries heavier than the whole cache are rejected.
+        assert!(!cache.insert(3, vec![0; 1001]));
+        assert!(cache.get(&3).is_none());
+        assert_eq!(cache.weight(), 201);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        cache.insert("apple", "red");
+        cache.insert("banana", "yellow");
+        assert_eq!(cache.remove(&"apple"), Some("red"));
+        assert_eq!(cache.remove(&"apple"), None);
+        assert!(cache.get(&"apple").is_none());
+        assert_opt_eq(cache.get(&"banana"), "yellow");
+        assert_eq!(cache.weight(), 1);
+    }
+
+    #[test]
+    fn test_remove_then_reinsert_skips_stale_slots() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        cache.insert(1, 1);
+        cache.remove(&1);
+        cache.insert(1, 2);
+
+        // Fill the cache so that both the stale and the live slot of key 1 reach the head of
+        // the small queue. Only the live entry is promoted after being accessed.
+        cache.get(&1);
+        for i in 2..30 {
+            cache.insert(i, i);
+        }
+        assert_opt_eq(cache.get(&1), 2);
+        assert!(cache.weight() <= 10);
+        assert_eq!(cache.weight(), cache.index.len() as u64);
+    }
+
+    #[test]
+    fn test_removed_slots_are_compacted() {
+        let mut cache = Cache::new(NonZeroUsize::new(100).unwrap());
+
+        for i in 0..10_000 {
+            cache.insert(i, i);
+            cache.remove(&i);
+        }
+        assert!(cache.small.len() <= 2);
+        assert_eq!(cache.weight(), 0);
+    }
+
+    #[test]
+    fn test_insert_or_update() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        assert_eq!(cache.insert_or_update("apple", "red"), None);
+        assert_opt_eq(cache.get(&"apple"), "red");
+        assert_eq!(cache.insert_or_update("apple", "green"), Some("red"));
+        assert_opt_eq(cache.get(&"apple"), "green");
+        assert_eq!(cache.weight(), 1);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        assert_eq!(cache.replace("apple", "red"), None);
+        assert!(cache.get(&"apple").is_none());
+
+        cache.insert("apple", "red");
+        assert_eq!(cache.replace("apple", "green"), Some("red"));
+        assert_opt_eq(cache.get(&"apple"), "green");
+    }
+
+    #[test]
+    fn test_update_weight_evicts() {
+        let mut cache = Cache::with_weigher(1000, LenWeigher);
+
+        for i in 0..10 {
+            cache.insert(i, vec![0; 90]);
+        }
+        assert_eq!(cache.insert_or_update(0, vec![0; 500]), Some(vec![0; 90]));
+        assert!(cache.weight() <= 1000);
+
+        // A value heavier than the cache removes the key.
+        assert!(cache.insert_or_update(1, vec![0; 1001]).is_some());
+        assert!(cache.get(&1).is_none());
+    }
+
+    #[test]
+    fn test_invalidate_if() {
+        let mut cache = Cache::new(NonZeroUsize::new(100).unwrap());
+
+        for i in 0..50 {
+            cache.insert(i, i * 10);
+        }
+        assert_eq!(cache.invalidate_if(|key, _| key % 2 == 0), 25);
+        assert!(cache.get(&0).is_none());
+        assert_opt_eq(cache.get(&1), 10);
+        assert_eq!(cache.weight(), 25);
+
+        for i in 50..300 {
+            cache.insert(i, i * 10);
+        }
+        assert!(cache.weight() <= 100);
+    }
+
+    /// Clock which only moves when advanced by the test.
+    struct MockClock {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+    }
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_insert_with_ttl() {
+        let clock = MockClock::new();
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap()).with_clock(clock.clone());
+
+        cache.insert_with_ttl("apple", "red", Duration::from_secs(10));
+        cache.insert("banana", "yellow");
+
+        clock.advance(Duration::from_secs(9));
+        assert_opt_eq(cache.get(&"apple"), "red");
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&"apple").is_none());
+        assert_opt_eq(cache.get(&"banana"), "yellow");
+
+        // An expired key can be inserted again.
+        assert!(cache.insert("apple", "green"));
+        assert_opt_eq(cache.get(&"apple"), "green");
+        assert_eq!(cache.weight(), 2);
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let clock = MockClock::new();
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap())
+            .with_default_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone());
+
+        cache.insert("apple", "red");
+        cache.insert_with_ttl("banana", "yellow", Duration::from_secs(60));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(cache.get(&"apple").is_none());
+        assert_eq!(cache.remove(&"apple"), None);
+        assert_eq!(cache.replace("apple", "green"), None);
+        assert_opt_eq(cache.get(&"banana"), "yellow");
+    }
+
+    #[test]
+    fn test_expired_entries_are_not_promoted() {
+        let clock = MockClock::new();
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap()).with_clock(clock.clone());
+
+        cache.insert_with_ttl(0, 0, Duration::from_secs(1));
+        cache.get(&0);
+        clock.advance(Duration::from_secs(1));
+
+        // Key 0 reaches the head of the small queue while expired, so it is dropped rather
+        // than promoted into main or remembered by the ghost queue.
+        for i in 1..=10 {
+            cache.insert(i, i);
+        }
+        assert!(cache.find(&0).is_none());
+        assert!(!cache.ghost.contains(&0));
+        assert!(cache.main.is_empty());
+    }
+
+    /// Listener recording every eviction it is notified of.
+    type EvictionLog = Arc<Mutex<Vec<(u64, u64, EvictionCause)>>>;
+
+    fn recording_cache(size: usize) -> (Cache<u64, u64>, EvictionLog) {
+        let log = EvictionLog::default();
+        let recorder = log.clone();
+        let cache = Cache::new(NonZeroUsize::new(size).unwrap()).with_eviction_listener(Arc::new(
+            move |key: &u64, value: &u64, cause| {
+                recorder.lock().unwrap().push((*key, *value, cause))
+            },
+        ));
+        (cache, log)
+    }
+
+    #[test]
+    fn test_eviction_listener_queue_evictions() {
+        let (mut cache, log) = recording_cache(10);
+
+        // Key 0 is accessed and promoted into main, key 1 is evicted from small.
+        cache.insert(0, 0);
+        cache.get(&0);
+        for i in 1..=10 {
+            cache.insert(i, i);
+        }
+        assert_eq!(
+            log.lock().unwrap().as_slice(),
+            &[(1, 1, EvictionCause::SmallEvicted)]
+        );
+
+        // Accessed keys are promoted until main takes over the cache, at which point key 0, whose
+        // frequency was reset on promotion, is the first to be evicted from main.
+        log.lock().unwrap().clear();
+        for i in 11..40 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        assert!(log
+            .lock()
+            .unwrap()
+            .contains(&(0, 0, EvictionCause::MainEvicted)));
+    }
+
+    #[test]
+    fn test_eviction_listener_explicit_causes() {
+        let (mut cache, log) = recording_cache(10);
+        let clock = MockClock::new();
+        cache = cache.with_clock(clock.clone());
+
+        cache.insert(1, 1);
+        cache.insert_or_update(1, 2);
+        cache.remove(&1);
+        cache.insert_with_ttl(2, 2, Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        cache.insert(2, 3);
+        assert_eq!(cache.invalidate_if(|key, _| *key == 2), 1);
+
+        assert_eq!(
+            log.lock().unwrap().as_slice(),
+            &[
+                (1, 1, EvictionCause::Replaced),
+                (1, 2, EvictionCause::Removed),
+                (2, 2, EvictionCause::Expired),
+                (2, 3, EvictionCause::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalidate_if_does_not_count_expired_entries() {
+        let clock = MockClock::new();
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap()).with_clock(clock.clone());
+
+        cache.insert_with_ttl(1, 1, Duration::from_secs(1));
+        cache.insert(2, 2);
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(cache.invalidate_if(|_, _| true), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        // Key 0 is promoted, key 1 is evicted into the ghost queue.
+        cache.insert(0, 0);
+        cache.get(&0);
+        for i in 1..=10 {
+            cache.insert(i, i);
+        }
+        cache.get(&1);
+        // Key 1 comes back from the ghost queue straight into main.
+        cache.insert(1, 1);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 12);
+        assert_eq!(stats.promotions, 1);
+        assert_eq!(stats.ghost_hits, 1);
+        assert_eq!(stats.small_evictions, 2);
+        assert_eq!(stats.main_evictions, 0);
+        assert_eq!(stats.small_len, 8);
+        assert_eq!(stats.main_len, 2);
+        assert_eq!(stats.ghost_len, 2);
+        assert_eq!(stats.small_weight + stats.main_weight, 10);
+        assert_eq!(stats.hit_ratio(), 0.5);
+
+        // Accessed entries in main are reinserted instead of being evicted from it.
+        for i in 11..40 {
+            cache.insert(i, i);
+            cache.get(&i);
+            cache.get(&0);
+        }
+        let stats = cache.stats();
+        assert!(stats.main_reinsertions > 0);
+        assert!(stats.main_evictions > 0);
+        assert_eq!(stats.small_len + stats.main_len, 10);
+    }
+
+    #[test]
+    fn test_config_clamping() {
+        let config = S3FifoConfig::new()
+            .with_small_ratio(2.0)
+            .with_ghost_ratio(-1.0)
+            .with_max_freq(0)
+            .with_promotion_threshold(5);
+        assert_eq!(config.small_ratio, 1.0);
+        assert_eq!(config.ghost_ratio, 0.0);
+        assert_eq!(config.max_freq, 1);
+        assert_eq!(config.promotion_threshold, 1);
+
+        let config = S3FifoConfig::new()
+            .with_promotion_threshold(3)
+            .with_max_freq(2);
+        assert_eq!(config.promotion_threshold, 2);
+    }
+
+    #[test]
+    fn test_config_small_ratio() {
+        let config = S3FifoConfig::new().with_small_ratio(0.5);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        assert_eq!(cache.max_small_weight, 5);
+
+        // Promote 0..5 into the main queue, leaving 6..=10 in the small queue.
+        for i in 0..5 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        for i in 5..=10 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.stats().promotions, 5);
+
+        // The small queue holds half of the cache, so it is still evicted from.
+        cache.insert(11, 11);
+        let stats = cache.stats();
+        assert_eq!(stats.small_evictions, 2);
+        assert_eq!(stats.main_evictions, 0);
+
+        // With a larger small queue, the main queue is evicted from instead.
+        let config = S3FifoConfig::new().with_small_ratio(0.9);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        for i in 0..5 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        for i in 5..=11 {
+            cache.insert(i, i);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.small_evictions, 1);
+        assert_eq!(stats.main_evictions, 1);
+    }
+
+    #[test]
+    fn test_config_promotion_threshold() {
+        let config = S3FifoConfig::new().with_promotion_threshold(2);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+
+        // Key 0 is accessed once, which is not enough to be promoted.
+        cache.insert(0, 0);
+        cache.get(&0);
+        // Key 1 is accessed twice, which is.
+        cache.insert(1, 1);
+        cache.get(&1);
+        cache.get(&1);
+        for i in 2..=11 {
+            cache.insert(i, i);
+        }
+        assert!(cache.get(&0).is_none());
+        assert_opt_eq(cache.get(&1), 1);
+        assert_eq!(cache.stats().promotions, 1);
+    }
+
+    #[test]
+    fn test_config_max_freq() {
+        let config = S3FifoConfig::new().with_max_freq(1);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+
+        cache.insert(0, 0);
+        for _ in 0..5 {
+            cache.get(&0);
+        }
+        assert_eq!(cache.entry(&0).unwrap().freq.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_config_without_ghost() {
+        let config = S3FifoConfig::new().with_ghost_ratio(0.0);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.stats().ghost_len, 0);
+        cache.insert(0, 0);
+        assert_eq!(cache.stats().ghost_hits, 0);
+    }
+
+    #[test]
+    fn test_adaptive_small_queue_grows_on_ghost_hits() {
+        let config = S3FifoConfig::new().with_adaptive(true);
+        let mut cache = Cache::with_config(NonZeroUsize::new(1000).unwrap(), config);
+        assert_eq!(cache.stats().small_capacity, 100);
+
+        // A loop slightly larger than the small queue only ever hits the ghost queue.
+        let mut previous = 100;
+        for _ in 0..20 {
+            for i in 0..150 {
+                if cache.get(&i).is_none() {
+                    cache.insert(i, i);
+                }
+            }
+            for i in 1000..2000 {
+                cache.insert(i, i);
+            }
+            let capacity = cache.stats().small_capacity;
+            assert!(capacity >= previous && capacity - previous <= 100);
+            previous = capacity;
+        }
+        assert!(previous > 100);
+        assert!(previous <= 500);
+    }
+
+    #[test]
+    fn test_adaptive_small_queue_shrinks_on_promotions() {
+        let config = S3FifoConfig::new().with_adaptive(true);
+        let mut cache = Cache::with_config(NonZeroUsize::new(1000).unwrap(), config);
+
+        // Every entry is accessed while in the small queue, so every departure is a promotion.
+        for i in 0..10_000 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        assert_eq!(cache.stats().small_capacity, 10);
+        assert!(cache.small_weight + cache.main_weight <= 1000);
+    }
+
+    #[test]
+    fn test_static_small_queue_is_not_tuned() {
+        let mut cache = Cache::new(NonZeroUsize::new(1000).unwrap());
+        for i in 0..10_000 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        assert_eq!(cache.stats().small_capacity, 100);
+    }
+
+    #[test]
+    fn test_ghost_queue_evicts_oldest_first() {
+        let mut ghost = GhostQueue::new(3);
+        for key in 0..5 {
+            ghost.push(&key, 1);
+        }
+        assert_eq!(ghost.len(), 3);
+        assert!(!ghost.contains(&0));
+        assert!(!ghost.contains(&1));
+        assert!(ghost.contains(&2));
+        assert!(ghost.contains(&4));
+    }
+
+    #[test]
+    fn test_ghost_queue_weight() {
+        let mut ghost = GhostQueue::new(10);
+        ghost.push(&"a", 4);
+        ghost.push(&"b", 4);
+        // Pushing 4 more drops the oldest key to stay within the capacity.
+        ghost.push(&"c", 4);
+        assert!(!ghost.contains(&"a"));
+        assert!(ghost.contains(&"b"));
+        assert_eq!(ghost.weight, 8);
+
+        // Keys heavier than the queue are not remembered.
+        ghost.push(&"d", 11);
+        assert!(!ghost.contains(&"d"));
+        assert_eq!(ghost.len(), 2);
+    }
+
+    #[test]
+    fn test_ghost_queue_duplicate_keys() {
+        let mut ghost = GhostQueue::new(3);
+        ghost.push(&0, 1);
+        ghost.push(&1, 1);
+        ghost.push(&0, 1);
+
+        // The oldest slot of key 0 is dropped, but its newer slot keeps it remembered.
+        ghost.push(&2, 1);
+        assert!(ghost.contains(&0));
+        ghost.push(&3, 1);
+        ghost.push(&4, 1);
+        assert!(!ghost.contains(&0));
+        assert_eq!(ghost.index.len(), 3);
+    }
+
+    #[test]
+    fn test_ghost_queue_false_positive_rate() {
+        let remembered = 100_000u64;
+        let mut ghost = GhostQueue::new(remembered);
+        for key in 0..remembered {
+            ghost.push(&key, 1);
+        }
+
+        // About remembered / 2^32 of the lookups for absent keys are expected to match, which is
+        // about 23 out of a million here.
+        let lookups = 1_000_000u64;
+        let false_positives = (remembered..remembered + lookups)
+            .filter(|key| ghost.contains(key))
+            .count();
+        assert!(false_positives < 100, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_eviction_budget_bounds_main_reinsertions() {
+        let config = S3FifoConfig::new().with_eviction_budget(4);
+        let mut cache = Cache::with_config(NonZeroUsize::new(100).unwrap(), config);
+
+        // Fill the main queue with entries at the maximum frequency.
+        for i in 0..100 {
+            cache.insert(i, i);
+            for _ in 0..4 {
+                cache.get(&i);
+            }
+        }
+        for i in 100..200 {
+            cache.insert(i, i);
+            for _ in 0..4 {
+                cache.get(&i);
+            }
+        }
+
+        // Every eviction from the hot main queue gives up after 4 reinsertions.
+        for i in 200..210 {
+            let before = cache.stats();
+            cache.insert(i, i);
+            let after = cache.stats();
+            assert!(after.main_reinsertions - before.main_reinsertions <= 4);
+            assert!(after.promotions - before.promotions <= 4);
+        }
+        assert!(cache.stats().forced_evictions > 0);
+        assert_eq!(cache.index.len(), 100);
+    }
+
+    #[test]
+    fn test_zero_eviction_budget_is_fifo() {
+        let config = S3FifoConfig::new().with_eviction_budget(0);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+
+        for i in 0..20 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.promotions, 0);
+        assert_eq!(stats.main_reinsertions, 0);
+        assert_eq!(stats.forced_evictions, 10);
+        for i in 10..20 {
+            assert_opt_eq(cache.get(&i), i);
+        }
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        assert_eq!(cache.get_or_insert_with(1, || 10), 10);
+        // The value is only computed on a miss.
+        assert_eq!(cache.get_or_insert_with(1, || unreachable!()), 10);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 1);
+    }
+
+    #[test]
+    fn test_try_get_or_insert_with() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+
+        assert_eq!(
+            cache.try_get_or_insert_with(1, || Err("failed")),
+            Err("failed")
+        );
+        assert!(cache.get(&1).is_none());
+
+        assert_eq!(
+            cache.try_get_or_insert_with(1, || Ok::<_, &str>(10)),
+            Ok(10)
+        );
+        assert_eq!(cache.try_get_or_insert_with(1, || Err("failed")), Ok(10));
+    }
+
+    #[test]
+    fn test_get_or_insert_with_too_heavy() {
+        let mut cache = Cache::with_weigher(10, LenWeigher);
+
+        assert_eq!(cache.get_or_insert_with(1, || vec![0; 11]).len(), 11);
+        assert!(cache.get(&1).is_none());
+    }
+
+    #[test]
+    fn test_peek_does_not_count_as_access() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        cache.insert(0, 0);
+        assert_opt_eq(cache.peek(&0), 0);
+        assert!(cache.peek(&1).is_none());
+        assert!(cache.contains_key(&0));
+        assert!(!cache.contains_key(&1));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 0);
+
+        // Key 0 was never accessed, so it is evicted rather than promoted.
+        for i in 1..=10 {
+            cache.insert(i, i);
+        }
+        assert!(!cache.contains_key(&0));
+        assert_eq!(cache.stats().promotions, 0);
+    }
+
+    #[test]
+    fn test_len_and_capacity() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 10);
+
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 10);
+        assert!(!cache.is_empty());
+    }
+
+    #[test]
+    fn test_borrowed_lookups() {
+        let mut cache: Cache<Vec<u8>, u64> = Cache::new(NonZeroUsize::new(10).unwrap());
+        cache.insert(b"apple".to_vec(), 1);
+
+        assert_opt_eq(cache.get(&b"apple"[..]), 1);
+        assert_opt_eq(cache.peek(&b"apple"[..]), 1);
+        assert!(cache.contains_key(&b"apple"[..]));
+        assert_eq!(cache.remove(&b"apple"[..]), Some(1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_queue_iterators() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.get(&0);
+        cache.get(&0);
+        cache.get(&5);
+        // Key 0 is promoted into main and key 1 is evicted.
+        cache.insert(10, 10);
+        cache.remove(&3);
+
+        let small: Vec<_> = cache.iter_small().map(|(k, _, f)| (*k, f)).collect();
+        assert_eq!(
+            small,
+            vec![
+                (2, 0),
+                (4, 0),
+                (5, 1),
+                (6, 0),
+                (7, 0),
+                (8, 0),
+                (9, 0),
+                (10, 0)
+            ]
+        );
+        let main: Vec<_> = cache.iter_main().map(|(k, v, f)| (*k, *v, f)).collect();
+        assert_eq!(main, vec![(0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_clear() {
+        let (mut cache, log) = recording_cache(10);
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        let evicted = log.lock().unwrap().len();
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+        assert_eq!(cache.iter_small().count() + cache.iter_main().count(), 0);
+        assert_eq!(cache.stats().ghost_len, 0);
+        assert_eq!(log.lock().unwrap().len(), evicted + 10);
+
+        // The cache is usable after being cleared.
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.slots.len(), 10);
+    }
+
+    #[test]
+    fn test_resize_shrink_follows_s3fifo_rules() {
+        let (mut cache, log) = recording_cache(100);
+        for i in 0..100 {
+            cache.insert(i, i);
+        }
+        // Accessed entries survive the shrink, the others are evicted from the small queue.
+        for i in 0..10 {
+            cache.get(&i);
+        }
+
+        cache.resize(10);
+        assert_eq!(cache.capacity(), 10);
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.stats().small_capacity, 1);
+        for i in 0..10 {
+            assert!(cache.contains_key(&i));
+        }
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 90);
+        assert!(log
+            .iter()
+            .all(|(_, _, cause)| *cause == EvictionCause::SmallEvicted));
+        assert!(cache.stats().ghost_len <= 9);
+    }
+
+    #[test]
+    fn test_shrink_to_keeps_capacity() {
+        let mut cache = Cache::new(NonZeroUsize::new(100).unwrap());
+        for i in 0..100 {
+            cache.insert(i, i);
+        }
+
+        assert!(cache.shrink_to(10));
+        assert_eq!(cache.capacity(), 100);
+        assert_eq!(cache.len(), 10);
+        // The room made is available to new entries.
+        for i in 100..190 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.stats().small_evictions, 90);
+
+        cache.pin(&189).unwrap();
+        assert!(!cache.shrink_to(0));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_resize_grow_keeps_entries() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+
+        cache.resize(100);
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.stats().small_capacity, 10);
+        for i in 10..100 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.stats().small_evictions, 0);
+    }
+
+    #[test]
+    fn test_resize_adaptive_keeps_small_share() {
+        let config = S3FifoConfig::new()
+            .with_small_ratio(0.2)
+            .with_adaptive(true);
+        let mut cache: Cache<u64, u64> =
+            Cache::with_config(NonZeroUsize::new(100).unwrap(), config);
+
+        cache.resize(1000);
+        assert_eq!(cache.stats().small_capacity, 200);
+        cache.resize(50);
+        assert_eq!(cache.stats().small_capacity, 10);
+    }
+
+    #[test]
+    fn test_ghost_queue_resize() {
+        let mut ghost = GhostQueue::new(10);
+        for key in 0..10 {
+            ghost.push(&key, 1);
+        }
+        ghost.resize(4);
+        assert_eq!(ghost.len(), 4);
+        assert!(!ghost.contains(&5));
+        assert!(ghost.contains(&6));
+    }
+
+    #[test]
+    fn test_pinned_entry_is_never_evicted() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        assert_eq!(cache.insert_pinned(0, 0), Ok(true));
+        cache.get(&0);
+
+        for i in 1..1000 {
+            cache.insert(i, i);
+            assert!(cache.contains_key(&0));
+        }
+        assert!(cache.weight() <= 10);
+
+        // Moving through the queues did not spend the frequency of the pinned entry.
+        let freq = cache
+            .iter_main()
+            .find(|(key, _, _)| **key == 0)
+            .map(|(_, _, freq)| freq);
+        assert_eq!(freq, Some(1));
+
+        assert!(cache.unpin(&0));
+        assert!(!cache.is_pinned(&0));
+        assert_eq!(cache.stats().pinned_weight, 0);
+    }
+
+    #[test]
+    fn test_pinning_is_limited() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        for i in 0..5 {
+            assert_eq!(cache.pin(&i), Ok(()));
+        }
+        assert_eq!(cache.pin(&5), Err(PinError::LimitExceeded));
+        assert_eq!(cache.insert_pinned(20, 20), Err(PinError::LimitExceeded));
+        assert_eq!(cache.pin(&42), Err(PinError::NotFound));
+        // Pinning twice does not count twice.
+        assert_eq!(cache.pin(&0), Ok(()));
+        assert_eq!(cache.stats().pinned_weight, 5);
+
+        // Removing a pinned entry gives its room back.
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.pin(&5), Ok(()));
+        assert!(cache.is_pinned(&5));
+        assert!(!cache.is_pinned(&6));
+    }
+
+    #[test]
+    fn test_main_queue_of_pinned_entries() {
+        let config = S3FifoConfig::new().with_max_pinned_ratio(0.8);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        for i in 0..8 {
+            assert_eq!(cache.insert_pinned(i, i), Ok(true));
+        }
+
+        // The pinned entries fill the main queue, so evictions fall back to the small queue.
+        for i in 100..200 {
+            assert!(cache.insert(i, i));
+        }
+        for i in 0..8 {
+            assert!(cache.contains_key(&i));
+        }
+        assert_eq!(cache.len(), 10);
+
+        // Nothing fits next to the pinned entries once they take the whole capacity.
+        cache.resize(8);
+        assert_eq!(cache.len(), 8);
+        assert!(!cache.insert(300, 300));
+    }
+
+    /// Weighs every entry as two units, so that entries skip a small queue of one unit.
+    struct DoubleWeigher;
+
+    impl Weigher<u64, u64> for DoubleWeigher {
+        fn weight(&self, _key: &u64, _value: &u64) -> u64 {
+            2
+        }
+    }
+
+    #[test]
+    fn test_costly_entry_does_extra_laps() {
+        let mut cache = Cache::with_weigher(10, DoubleWeigher);
+        cache.insert(0, 0);
+        assert!(cache.insert_with_cost(1, 1, 7 * DEFAULT_COST_UNIT));
+        for i in 2..5 {
+            cache.insert(i, i);
+        }
+
+        // Every entry is in the main queue with a frequency of zero, and only the costly one has
+        // credits left, for three extra laps.
+        for i in 5..12 {
+            cache.insert(i, i);
+        }
+        assert!(cache.contains_key(&1));
+        for i in [0, 2, 3, 4] {
+            assert!(!cache.contains_key(&i));
+        }
+
+        // Once its credits are spent, the costly entry is evicted like the others.
+        for i in 12..20 {
+            cache.insert(i, i);
+        }
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.stats().main_reinsertions, 3);
+    }
+
+    #[test]
+    fn test_cost_credits() {
+        let config = S3FifoConfig::new().with_cost_unit(100);
+        let cache: Cache<u64, u64> = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        assert_eq!(cache.cost_credits(0), 0);
+        assert_eq!(cache.cost_credits(99), 0);
+        assert_eq!(cache.cost_credits(100), 1);
+        assert_eq!(cache.cost_credits(300), 2);
+        // Credits are capped at the maximum frequency.
+        assert_eq!(cache.cost_credits(u64::MAX), DEFAULT_MAX_FREQUENCY);
+    }
+
+    #[test]
+    fn test_bytes_saved() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        cache.insert_with_cost(1, 1, 100);
+        cache.insert(2, 2);
+        cache.get(&1);
+        cache.get(&1);
+        cache.get(&2);
+        cache.get(&3);
+
+        let stats = cache.stats();
+        assert_eq!(stats.bytes_saved, 200);
+        assert_eq!(stats.hits, 3);
+    }
+
+    #[test]
+    fn test_admission_filter_keeps_scans_out() {
+        let config = S3FifoConfig::new().with_admission_filter(true);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        for i in 0..10u64 {
+            cache.insert(i, i);
+            cache.get(&i);
+            cache.get(&i);
+        }
+
+        for i in 100..200u64 {
+            assert!(!cache.insert(i, i));
+        }
+        for i in 0..10u64 {
+            assert!(cache.contains_key(&i));
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.rejected, 100);
+        assert_eq!(stats.admitted, 0);
+        assert_eq!(stats.insertions, 10);
+    }
+
+    #[test]
+    fn test_admission_filter_admits_returning_keys() {
+        let config = S3FifoConfig::new().with_admission_filter(true);
+        let mut cache = Cache::with_config(NonZeroUsize::new(10).unwrap(), config);
+        for i in 0..10u64 {
+            cache.insert(i, i);
+        }
+
+        // A new key is as frequent as the next victim, so it is rejected the first time and
+        // admitted the second time it is inserted.
+        assert!(!cache.insert(100, 100));
+        assert!(cache.insert(100, 100));
+        assert!(cache.contains_key(&100));
+        assert!(!cache.contains_key(&0));
+
+        let stats = cache.stats();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.admitted, 1);
+    }
+
+    #[test]
+    fn test_admission_filter_disabled_by_default() {
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..100u64 {
+            assert!(cache.insert(i, i));
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.admitted + stats.rejected, 0);
+    }
+
+    #[test]
+    fn test_frequency_sketch_ages() {
+        let sketch = FrequencySketch::new(16);
+        for _ in 0..20 {
+            sketch.increment(42);
+        }
+        assert_eq!(sketch.estimate(42), MAX_SKETCH_COUNT);
+        assert_eq!(sketch.estimate(7), 0);
+
+        // Reaching the sample size halves every counter.
+        let mut hash = 0u64;
+        while sketch.estimate(42) == MAX_SKETCH_COUNT {
+            hash += 1;
+            sketch.increment(hash.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        }
+        assert_eq!(sketch.estimate(42), MAX_SKETCH_COUNT / 2);
+        assert!(hash < sketch.sample_size);
+    }
+
+    #[test]
+    fn test_warm_start_round_trip() {
+        let dir = tempdir::TempDir::new("s3fifo").unwrap();
+        let path = dir.path().join("cache.warm");
+
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..10u64 {
+            cache.insert(i, i * 10);
+        }
+        cache.get(&0);
+        cache.get(&0);
+        cache.get(&5);
+        // Key 0 is promoted into main and key 1 is evicted into the ghost queue.
+        cache.insert(10, 100);
+        cache.save_warm_start(&path, 7).unwrap();
+
+        let mut restored = Cache::new(NonZeroUsize::new(10).unwrap());
+        assert_eq!(restored.load_warm_start(&path, 7).unwrap(), 10);
+        let queue = |iter: Box<dyn Iterator<Item = (&u64, &u64, u8)> + '_>| {
+            iter.map(|(k, v, f)| (*k, *v, f)).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            queue(Box::new(restored.iter_small())),
+            queue(Box::new(cache.iter_small()))
+        );
+        assert_eq!(
+            queue(Box::new(restored.iter_main())),
+            queue(Box::new(cache.iter_main()))
+        );
+        assert_eq!(restored.weight(), cache.weight());
+
+        // The ghost queue is restored too, so key 1 comes back into main.
+        restored.insert(1, 10);
+        assert_eq!(restored.stats().ghost_hits, 1);
+    }
+
+    #[test]
+    fn test_warm_start_skips_what_does_not_fit() {
+        let dir = tempdir::TempDir::new("s3fifo").unwrap();
+        let path = dir.path().join("cache.warm");
+
+        let mut cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        for i in 0..10u64 {
+            cache.insert(i, i);
+        }
+        cache.insert_with_ttl(10, 10, Duration::from_secs(60));
+        cache.save_warm_start(&path, 0).unwrap();
+
+        let mut restored: Cache<u64, u64> = Cache::new(NonZeroUsize::new(5).unwrap());
+        assert_eq!(restored.load_warm_start(&path, 0).unwrap(), 5);
+        assert!(!restored.contains_key(&10));
+
+        // A mismatched tag leaves the cache untouched.
+        let mut empty: Cache<u64, u64> = Cache::new(NonZeroUsize::new(5).unwrap());
+        assert!(empty.load_warm_start(&path, 1).is_err());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_weighted_eviction_frees_enough_room() {
+        let mut cache = Cache::with_weigher(1000, LenWeigher);
+
+        for i in 0..10 {
+            assert!(cache.insert(i, vec![0; 90]));
+        }
+        assert_eq!(cache.weight(), 900);
+
+        // A large entry evicts as many small entries as needed to fit.
+        assert!(cache.insert(100, vec![0; 500]));
+        assert!(cache.weight() <= 1000);
+        assert!(cache.get(&100).is_some());
+    }
+
+    #[test]
+    fn test_mrc() {
+        let mut cache = Cache::with_weigher(1000, LenWeigher);
+        cache.enable_mrc(1024);
+        // 15 values of 100 bytes looked up in a loop need 1500 bytes to hit.
+        for _ in 0..10 {
+            for i in 0..15u64 {
+                cache.get_or_insert_with(i, || vec![0; 100]);
+            }
+        }
+        let mrc = cache.stats().mrc.unwrap();
+        assert_eq!(mrc.lookups, 150);
+        assert_eq!(mrc.hit_ratio(1.0), Some(0.0));
+        assert_eq!(mrc.hit_ratio(2.0), Some(0.9));
+        assert_eq!(mrc.hits[5], 135);
+
+        // Enabling again starts from scratch.
+        cache.enable_mrc(1024);
+        assert_eq!(cache.stats().mrc.unwrap().lookups, 0);
+        cache.disable_mrc();
+        assert_eq!(cache.stats().mrc, None);
+    }
+
+    #[test]
+    fn test_policy() {
+        fn fill<P: Policy<u64, u64>>(policy: &mut P) {
+            for i in 0..10 {
+                policy.insert(i, i);
+            }
+        }
+
+        let mut cache: Cache<u64, u64> = Cache::new(NonZeroUsize::new(5).unwrap());
+        fill(&mut cache);
+        assert_eq!(Policy::len(&cache), 5);
+        assert_eq!(Policy::get(&mut cache, &9), Some(&9));
+        assert!(!Policy::insert(&mut cache, 9, 90));
+        assert_eq!(Policy::remove(&mut cache, &9), Some(9));
+        assert_eq!(Policy::stats(&cache).hits, 1);
+        assert_eq!(Policy::stats(&cache).insertions, 10);
     }
 }
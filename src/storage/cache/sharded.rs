@@ -0,0 +1,892 @@
+/// A thread-safe S3-FIFO cache which spreads its keys over several independently locked shards.
+use hashbrown::HashMap;
+use parking_lot::{Mutex, RwLock};
+use std::borrow::Borrow;
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::storage::cache::s3fifo::{
+    fingerprint, Cache, CacheStats, Clock, EvictionListener, PinError, S3FifoConfig, UnitWeigher,
+    Weigher,
+};
+use crate::storage::cache::warm_start::{Persist, WarmState};
+
+/// Number of shards created per available CPU when the shard count is not given explicitly.
+const SHARDS_PER_CPU: usize = 4;
+
+/// Returns the number of shards used when it is not given explicitly, derived from the available
+/// parallelism.
+pub fn default_shard_count() -> NonZeroUsize {
+    let cpus = available_parallelism().map_or(1, NonZeroUsize::get);
+    NonZeroUsize::new((cpus * SHARDS_PER_CPU).next_power_of_two()).unwrap()
+}
+
+/// ShardedCache hashes every key to one of N shards, each of which is an S3-FIFO `Cache`
+/// guarded by its own `RwLock`.
+///
+/// Hits only take the shared lock of one shard, as the access frequency of an entry is an atomic
+/// counter. Inserts and the evictions they cause take the exclusive lock of a single shard, so
+/// writers on different shards never contend.
+///
+/// The capacity is split evenly between the shards, so an entry must fit within the capacity of
+/// a single shard to be admitted.
+///
+/// Values can be loaded asynchronously through `get_or_load`, which runs a single load per key at
+/// a time: tasks missing on a key which is already being loaded wait for that load instead of
+/// starting their own. `get_or_load_blocking` does the same for synchronous callers, blocking the
+/// thread instead.
+pub struct ShardedCache<K, V, W = UnitWeigher>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    shards: Box<[RwLock<Cache<K, V, W>>]>,
+    /// Keys being loaded by `get_or_load`, with the lock held by their loader.
+    loading: Mutex<HashMap<K, Arc<AsyncMutex<()>>>>,
+    /// Keys being loaded by `get_or_load_blocking`, with the lock held by their loader.
+    blocking_loading: Mutex<HashMap<K, Arc<Mutex<()>>>>,
+}
+
+/// Removes the key of a load from the keys being loaded once no other task waits for it, even if
+/// the load was cancelled or panicked.
+struct LoadingGuard<'a, K: Eq + Hash, L> {
+    loading: &'a Mutex<HashMap<K, Arc<L>>>,
+    key: &'a K,
+    flight: Arc<L>,
+}
+
+impl<K: Eq + Hash, L> Drop for LoadingGuard<'_, K, L> {
+    fn drop(&mut self) {
+        let mut loading = self.loading.lock();
+        // One reference is held by the map and one by this guard.
+        if Arc::strong_count(&self.flight) == 2 {
+            loading.remove(self.key);
+        }
+    }
+}
+
+impl<K, V> ShardedCache<K, V>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    /// Creates a new sharded cache with the given maximum size, using a number of shards derived
+    /// from the available parallelism.
+    pub fn new(max_cache_size: NonZeroUsize) -> Self {
+        Self::with_weigher(max_cache_size.get() as u64, UnitWeigher)
+    }
+
+    /// Creates a new sharded cache with the given maximum size and number of shards.
+    pub fn with_shards(max_cache_size: NonZeroUsize, shards: NonZeroUsize) -> Self {
+        Self::with_shards_and_weigher(max_cache_size.get() as u64, shards, UnitWeigher)
+    }
+
+    /// Creates a new sharded cache with the given maximum size and S3-FIFO parameters, which are
+    /// applied to every shard.
+    pub fn with_config(max_cache_size: NonZeroUsize, config: S3FifoConfig) -> Self {
+        Self::with_weigher_and_config(max_cache_size.get() as u64, UnitWeigher, config)
+    }
+}
+
+impl<K, V, W> ShardedCache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    W: Weigher<K, V> + Clone,
+{
+    /// Creates a new sharded cache holding entries up to the given total weight, using a number
+    /// of shards derived from the available parallelism.
+    pub fn with_weigher(max_weight: u64, weigher: W) -> Self {
+        Self::with_weigher_and_config(max_weight, weigher, S3FifoConfig::default())
+    }
+
+    /// Creates a new sharded cache holding entries up to the given total weight, with the given
+    /// S3-FIFO parameters applied to every shard.
+    pub fn with_weigher_and_config(max_weight: u64, weigher: W, config: S3FifoConfig) -> Self {
+        Self::build(max_weight, default_shard_count(), weigher, config)
+    }
+
+    /// Creates a new sharded cache holding entries up to the given total weight, split over the
+    /// given number of shards. The number of shards is capped so that every shard can hold at
+    /// least a weight of one.
+    pub fn with_shards_and_weigher(max_weight: u64, shards: NonZeroUsize, weigher: W) -> Self {
+        Self::build(max_weight, shards, weigher, S3FifoConfig::default())
+    }
+
+    fn build(max_weight: u64, shards: NonZeroUsize, weigher: W, config: S3FifoConfig) -> Self {
+        let max_weight = max_weight.max(1);
+        let shard_count = (shards.get() as u64).min(max_weight);
+        let shard_weight = max_weight.div_ceil(shard_count);
+
+        Self {
+            shards: (0..shard_count)
+                .map(|_| {
+                    RwLock::new(Cache::with_weigher_and_config(
+                        shard_weight,
+                        weigher.clone(),
+                        config,
+                    ))
+                })
+                .collect(),
+            loading: Mutex::new(HashMap::new()),
+            blocking_loading: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the time to live given to entries inserted without an explicit one.
+    pub fn with_default_ttl(self, ttl: Duration) -> Self {
+        self.map_shards(|shard| shard.with_default_ttl(ttl))
+    }
+
+    /// Sets the clock used to expire entries.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        self.map_shards(|shard| shard.with_clock(clock.clone()))
+    }
+
+    /// Sets the listener notified of every entry leaving the cache. The listener is called while
+    /// the exclusive lock of the shard is held.
+    pub fn with_eviction_listener(self, listener: Arc<dyn EvictionListener<K, V>>) -> Self {
+        self.map_shards(|shard| shard.with_eviction_listener(listener.clone()))
+    }
+
+    /// Returns the number of shards of the cache.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns a clone of the value of the given key if it exists in the cache.
+    /// Only the shared lock of the shard owning the key is taken.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().get(key).cloned()
+    }
+
+    /// Returns a clone of the value of the given key if it exists in the cache, without counting
+    /// as an access.
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().peek(key).cloned()
+    }
+
+    /// Returns true if the given key is present and not expired, without counting as an access.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().contains_key(key)
+    }
+
+    /// Returns a clone of the value of the given key, computing it with `f` and inserting it on a
+    /// miss. `f` runs without any lock held, so concurrent misses on the same key may each compute
+    /// the value. The first value inserted is kept, but every caller gets the value it computed.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Returns a clone of the value of the given key, computing it with `f` and inserting it on a
+    /// miss. If `f` fails, its error is returned and nothing is cached.
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+        let value = f()?;
+        self.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Returns a clone of the value of the given key, awaiting `load` and inserting its value on a
+    /// miss. Only one load per key runs at a time: a task missing on a key which is already being
+    /// loaded waits for that load to finish, and only awaits its own `load` if the value is still
+    /// missing afterwards, e.g. because the other load failed.
+    pub async fn get_or_load<F, E>(&self, key: K, load: F) -> Result<V, E>
+    where
+        F: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let flight = self.loading.lock().entry(key.clone()).or_default().clone();
+        let guard = LoadingGuard {
+            loading: &self.loading,
+            key: &key,
+            flight,
+        };
+        let _permit = guard.flight.lock().await;
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+        let value = load.await?;
+        self.insert(key.clone(), value.clone());
+        Ok(value)
+    }
+
+    /// Returns a clone of the value of the given key, calling `load` and inserting its value on a
+    /// miss, along with the cost `load` returns for it (see `insert_with_cost`). Like
+    /// `get_or_load`, only one load per key runs at a time: a thread missing on a key which is
+    /// already being loaded blocks until that load finishes, and only calls its own `load` if the
+    /// value is still missing afterwards.
+    pub fn get_or_load_blocking<F, E>(&self, key: K, load: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<(V, u64), E>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let flight = self
+            .blocking_loading
+            .lock()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = LoadingGuard {
+            loading: &self.blocking_loading,
+            key: &key,
+            flight,
+        };
+        let _permit = guard.flight.lock();
+        // The miss was already counted by the lookup above.
+        if let Some(value) = self.peek(&key) {
+            return Ok(value);
+        }
+        let (value, cost) = load()?;
+        self.insert_with_cost(key.clone(), value.clone(), cost);
+        Ok(value)
+    }
+
+    /// Inserts a new entry with the given key and value into the cache.
+    /// Only the exclusive lock of the shard owning the key is taken.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        self.shard(&key).write().insert(key, value)
+    }
+
+    /// Inserts a new entry with the given key and value into the cache, expiring after `ttl`.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> bool {
+        self.shard(&key).write().insert_with_ttl(key, value, ttl)
+    }
+
+    /// Inserts the given key and value into the cache, or updates the value if the key is already
+    /// present. Returns the previous value, if any.
+    pub fn insert_or_update(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().insert_or_update(key, value)
+    }
+
+    /// Replaces the value of the given key if it is present in the cache.
+    /// Returns the previous value, or `None` if the key is absent.
+    pub fn replace(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().replace(key, value)
+    }
+
+    /// Inserts a new entry along with the cost of refetching it on a miss.
+    /// See `Cache::insert_with_cost`.
+    pub fn insert_with_cost(&self, key: K, value: V, cost: u64) -> bool {
+        self.shard(&key).write().insert_with_cost(key, value, cost)
+    }
+
+    /// Inserts a new pinned entry, or pins the entry of the key if it is already present.
+    /// Pinned entries are limited to a share of the capacity of their shard.
+    /// See `Cache::insert_pinned`.
+    pub fn insert_pinned(&self, key: K, value: V) -> Result<bool, PinError> {
+        self.shard(&key).write().insert_pinned(key, value)
+    }
+
+    /// Pins the entry of the given key, so that it is never evicted. See `Cache::pin`.
+    pub fn pin<Q>(&self, key: &Q) -> Result<(), PinError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().pin(key)
+    }
+
+    /// Unpins the entry of the given key. Returns false if the key is absent or was not pinned.
+    pub fn unpin<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().unpin(key)
+    }
+
+    /// Returns true if the given key is present and pinned.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().is_pinned(key)
+    }
+
+    /// Removes the given key from the cache, returning its value if it was present.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().remove(key)
+    }
+
+    /// Removes every entry for which the predicate returns true, locking one shard at a time.
+    /// Returns the number of removed live entries, see `Cache::invalidate_if`.
+    pub fn invalidate_if<F>(&self, mut predicate: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().invalidate_if(&mut predicate))
+            .sum()
+    }
+
+    /// Returns the statistics of the cache, summed over all shards.
+    pub fn stats(&self) -> CacheStats {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().stats())
+            .fold(CacheStats::default(), |total, stats| total + stats)
+    }
+
+    /// Returns the total weight of the entries in the cache.
+    pub fn weight(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.read().weight()).sum()
+    }
+
+    /// Returns the number of entries in the cache, summed over all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// Returns true if no shard holds any entry.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().is_empty())
+    }
+
+    /// Returns the maximum total weight of the entries in the cache, summed over all shards.
+    pub fn capacity(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().capacity())
+            .sum()
+    }
+
+    /// Changes the maximum total weight of the entries in the cache, which is split evenly between
+    /// the shards as at construction, locking one shard at a time. The number of shards does not
+    /// change, so every shard keeps a capacity of at least one.
+    pub fn resize(&self, max_weight: u64) {
+        let shard_weight = max_weight.max(1).div_ceil(self.shards.len() as u64);
+        for shard in self.shards.iter() {
+            shard.write().resize(shard_weight);
+        }
+    }
+
+    /// Starts estimating the miss-ratio curve of every shard, following at most `max_samples`
+    /// keys in total, and drops any previous estimate. The curves of the shards add up in
+    /// `CacheStats::mrc`.
+    pub fn enable_mrc(&self, max_samples: usize) {
+        let shard_samples = max_samples.div_ceil(self.shards.len());
+        for shard in self.shards.iter() {
+            shard.write().enable_mrc(shard_samples);
+        }
+    }
+
+    /// Stops estimating the miss-ratio curve of every shard.
+    pub fn disable_mrc(&self) {
+        for shard in self.shards.iter() {
+            shard.write().disable_mrc();
+        }
+    }
+
+    /// Removes every entry from the cache, locking one shard at a time.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write().clear();
+        }
+    }
+
+    /// Rebuilds every shard with the given function.
+    fn map_shards<F>(self, f: F) -> Self
+    where
+        F: Fn(Cache<K, V, W>) -> Cache<K, V, W>,
+    {
+        Self {
+            shards: self
+                .shards
+                .into_vec()
+                .into_iter()
+                .map(|shard| RwLock::new(f(shard.into_inner())))
+                .collect(),
+            loading: self.loading,
+            blocking_loading: self.blocking_loading,
+        }
+    }
+
+    /// Returns the shard owning the given key.
+    fn shard<Q>(&self, key: &Q) -> &RwLock<Cache<K, V, W>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        &self.shards[self.shard_index(fingerprint(key))]
+    }
+
+    /// Returns the index of the shard owning the keys with the given fingerprint. Shards are
+    /// picked by fingerprint rather than by a random hasher so that the ghost fingerprints of a
+    /// warm-start file are restored into the shard which owns their key.
+    fn shard_index(&self, fingerprint: u32) -> usize {
+        fingerprint as usize % self.shards.len()
+    }
+}
+
+impl<K, V, W> ShardedCache<K, V, W>
+where
+    K: PartialEq + Eq + Hash + Clone + Debug + Persist,
+    V: Clone + Debug + Persist,
+    W: Weigher<K, V> + Clone,
+{
+    /// Saves the contents of every shard to a single warm-start file, locking one shard at a
+    /// time. See `Cache::save_warm_start`.
+    pub fn save_warm_start(&self, path: &Path, tag: u64) -> io::Result<()> {
+        let mut state = WarmState::default();
+        for shard in self.shards.iter() {
+            state.extend(shard.read().warm_state());
+        }
+        state.save(path, tag)
+    }
+
+    /// Loads a warm-start file saved with the same `tag` into the cache, and returns the number of
+    /// restored entries. The file may have been saved with another number of shards, as every
+    /// entry is restored into the shard which owns its key. See `Cache::load_warm_start`.
+    pub fn load_warm_start(&self, path: &Path, tag: u64) -> io::Result<usize> {
+        let state = WarmState::load(path, tag)?;
+        let mut states: Vec<WarmState<K, V>> = (0..self.shards.len())
+            .map(|_| WarmState::default())
+            .collect();
+        for entry in state.small {
+            states[self.shard_index(fingerprint(&entry.0))]
+                .small
+                .push(entry);
+        }
+        for entry in state.main {
+            states[self.shard_index(fingerprint(&entry.0))]
+                .main
+                .push(entry);
+        }
+        for ghost in state.ghost {
+            states[self.shard_index(ghost.0)].ghost.push(ghost);
+        }
+        Ok(self
+            .shards
+            .iter()
+            .zip(states)
+            .map(|(shard, state)| shard.write().restore(state))
+            .sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+
+        assert!(cache.insert("apple", "red"));
+        assert!(!cache.insert("apple", "green"));
+        assert_eq!(cache.get(&"apple"), Some("red"));
+        assert_eq!(cache.get(&"banana"), None);
+    }
+
+    #[test]
+    fn test_remove_and_update() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.insert_or_update(1, 10), Some(1));
+        assert_eq!(cache.replace(2, 20), Some(2));
+        assert_eq!(cache.replace(0, 0), None);
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&2), Some(20));
+
+        assert_eq!(cache.invalidate_if(|key, _| *key >= 5), 5);
+        assert_eq!(cache.weight(), 4);
+    }
+
+    #[test]
+    fn test_inspection() {
+        let cache: ShardedCache<Vec<u8>, u64> = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 64);
+
+        for i in 0..10u64 {
+            cache.insert(i.to_be_bytes().to_vec(), i);
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.peek(&5u64.to_be_bytes()[..]), Some(5));
+        assert!(cache.contains_key(&5u64.to_be_bytes()[..]));
+        assert_eq!(cache.get(&5u64.to_be_bytes()[..]), Some(5));
+        assert_eq!(cache.stats().hits, 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(!cache.contains_key(&5u64.to_be_bytes()[..]));
+    }
+
+    #[test]
+    fn test_resize() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+        for i in 0..64 {
+            cache.insert(i, i);
+        }
+
+        cache.resize(16);
+        assert_eq!(cache.capacity(), 16);
+        assert!(cache.len() <= 16);
+
+        cache.resize(128);
+        assert_eq!(cache.capacity(), 128);
+        for i in 0..128 {
+            cache.insert(i, i);
+        }
+        assert!(cache.len() > 64);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        )
+        .with_default_ttl(Duration::ZERO);
+
+        // Entries with a zero time to live are expired as soon as they are inserted.
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1), None);
+
+        cache.insert_with_ttl(2, 2, Duration::from_secs(3600));
+        assert_eq!(cache.get(&2), Some(2));
+    }
+
+    #[test]
+    fn test_eviction_listener() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let evicted = Arc::new(AtomicUsize::new(0));
+        let counter = evicted.clone();
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        )
+        .with_eviction_listener(Arc::new(move |_: &u64, _: &u64, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        for i in 0..1000 {
+            cache.insert(i, i);
+        }
+        assert_eq!(
+            evicted.load(Ordering::Relaxed) as u64 + cache.weight(),
+            1000
+        );
+    }
+
+    #[test]
+    fn test_shard_count_is_capped_by_size() {
+        let cache: ShardedCache<u64, u64> = ShardedCache::with_shards(
+            NonZeroUsize::new(2).unwrap(),
+            NonZeroUsize::new(16).unwrap(),
+        );
+        assert_eq!(cache.shard_count(), 2);
+
+        let cache: ShardedCache<u64, u64> = ShardedCache::new(NonZeroUsize::new(1 << 16).unwrap());
+        assert!(cache.shard_count().is_power_of_two());
+    }
+
+    #[test]
+    fn test_config_is_applied_to_every_shard() {
+        let config = S3FifoConfig::new().with_ghost_ratio(0.0);
+        let cache = ShardedCache::with_config(NonZeroUsize::new(64).unwrap(), config);
+
+        for i in 0..1000 {
+            cache.insert(i, i);
+        }
+        assert!(cache.stats().small_evictions > 0);
+        assert_eq!(cache.stats().ghost_len, 0);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+
+        assert_eq!(cache.get_or_insert_with(1, || 10), 10);
+        assert_eq!(cache.get_or_insert_with(1, || unreachable!()), 10);
+        assert_eq!(
+            cache.try_get_or_insert_with(2, || Err("failed")),
+            Err("failed")
+        );
+        assert_eq!(cache.get(&2), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_get_or_load_is_single_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache = Arc::new(ShardedCache::new(NonZeroUsize::new(64).unwrap()));
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let cache = cache.clone();
+                let loads = loads.clone();
+                tokio::spawn(async move {
+                    cache
+                        .get_or_load(1, async {
+                            loads.fetch_add(1, Ordering::Relaxed);
+                            tokio::task::yield_now().await;
+                            Ok::<_, ()>(10)
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Ok(10));
+        }
+
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert!(cache.loading.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_load_retries_after_failure() {
+        let cache = ShardedCache::new(NonZeroUsize::new(64).unwrap());
+
+        assert_eq!(
+            cache.get_or_load(1, async { Err("failed") }).await,
+            Err("failed")
+        );
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(
+            cache.get_or_load(1, async { Ok::<_, &str>(10) }).await,
+            Ok(10)
+        );
+        assert_eq!(cache.get(&1), Some(10));
+        assert!(cache.loading.lock().is_empty());
+    }
+
+    #[test]
+    fn test_get_or_load_blocking_is_single_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Barrier;
+
+        let cache = ShardedCache::new(NonZeroUsize::new(64).unwrap());
+        let loads = AtomicUsize::new(0);
+        let barrier = Barrier::new(16);
+
+        std::thread::scope(|scope| {
+            for _ in 0..16 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    let value = cache.get_or_load_blocking(1, || {
+                        loads.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(Duration::from_millis(10));
+                        Ok::<_, ()>((10, 1))
+                    });
+                    assert_eq!(value, Ok(10));
+                });
+            }
+        });
+
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats().insertions, 1);
+        assert!(cache.blocking_loading.lock().is_empty());
+    }
+
+    #[test]
+    fn test_get_or_load_blocking_retries_after_failure() {
+        let cache = ShardedCache::new(NonZeroUsize::new(64).unwrap());
+
+        assert_eq!(
+            cache.get_or_load_blocking(1, || Err("failed")),
+            Err("failed")
+        );
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(
+            cache.get_or_load_blocking(1, || Ok::<_, &str>((10, 1))),
+            Ok(10)
+        );
+        assert_eq!(cache.get(&1), Some(10));
+        assert!(cache.blocking_loading.lock().is_empty());
+    }
+
+    #[derive(Clone, Copy)]
+    struct LenWeigher;
+
+    impl Weigher<u64, Vec<u8>> for LenWeigher {
+        fn weight(&self, _key: &u64, value: &Vec<u8>) -> u64 {
+            value.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_weighted_capacity() {
+        let cache =
+            ShardedCache::with_shards_and_weigher(4000, NonZeroUsize::new(4).unwrap(), LenWeigher);
+
+        for i in 0..1000 {
+            cache.insert(i, vec![0; 10]);
+        }
+        assert!(cache.weight() <= 4000);
+
+        // Entries heavier than a single shard are rejected.
+        assert!(!cache.insert(1000, vec![0; 1001]));
+    }
+
+    #[test]
+    fn test_warm_start_across_shard_counts() {
+        let dir = tempdir::TempDir::new("sharded").unwrap();
+        let path = dir.path().join("cache.warm");
+
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(1024).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+        for i in 0..100u64 {
+            cache.insert(i, i);
+        }
+        cache.save_warm_start(&path, 1).unwrap();
+
+        let restored: ShardedCache<u64, u64> = ShardedCache::with_shards(
+            NonZeroUsize::new(1024).unwrap(),
+            NonZeroUsize::new(8).unwrap(),
+        );
+        assert_eq!(restored.load_warm_start(&path, 1).unwrap(), 100);
+        for i in 0..100u64 {
+            assert_eq!(restored.peek(&i), Some(i));
+        }
+        assert!(restored.load_warm_start(&path, 2).is_err());
+    }
+
+    #[test]
+    fn test_pinned_entries_stay() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(64).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+        assert_eq!(cache.insert_pinned(0u64, 0u64), Ok(true));
+        cache.insert(1, 1);
+        assert_eq!(cache.pin(&1), Ok(()));
+        assert_eq!(cache.pin(&2), Err(PinError::NotFound));
+
+        for i in 100..1000u64 {
+            cache.insert(i, i);
+        }
+        assert!(cache.is_pinned(&0) && cache.is_pinned(&1));
+        assert_eq!(cache.stats().pinned_weight, 2);
+
+        assert!(cache.unpin(&1));
+        assert!(!cache.unpin(&1));
+        assert_eq!(cache.stats().pinned_weight, 1);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let cache = Arc::new(ShardedCache::new(NonZeroUsize::new(1024).unwrap()));
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                thread::spawn(move || {
+                    for i in 0..1000u64 {
+                        let key = t * 1000 + i;
+                        cache.insert(key, key);
+                        if let Some(value) = cache.get(&key) {
+                            assert_eq!(value, key);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 8000);
+        assert_eq!(stats.insertions, 8000);
+        assert_eq!(stats.small_len + stats.main_len, cache.weight());
+    }
+
+    #[test]
+    fn test_mrc() {
+        let cache = ShardedCache::with_shards(
+            NonZeroUsize::new(512).unwrap(),
+            NonZeroUsize::new(4).unwrap(),
+        );
+        assert_eq!(cache.stats().mrc, None);
+
+        cache.enable_mrc(4096);
+        // A loop over 800 keys misses at the current size, and hits at twice the size.
+        for _ in 0..10 {
+            for i in 0..800u64 {
+                cache.get_or_insert_with(i, || i);
+            }
+        }
+        let mrc = cache.stats().mrc.unwrap();
+        assert_eq!(mrc.lookups, 8000);
+        assert!(mrc.hit_ratio(1.0).unwrap() < 0.1);
+        assert!(mrc.hit_ratio(2.0).unwrap() > 0.8);
+
+        cache.disable_mrc();
+        assert_eq!(cache.stats().mrc, None);
+    }
+}
@@ -0,0 +1,239 @@
+/// This is an implementation of SIEVE from "SIEVE is Simpler than LRU: an Efficient Turn-Key
+/// Eviction Algorithm for Web Caches" by Yazhuo Zhang, et al. It is used as a point of comparison
+/// for S3-FIFO.
+use hashbrown::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+
+use crate::storage::cache::{s3fifo::CacheStats, Policy};
+
+/// Marks the absence of a node in the links of the queue.
+const NIL: usize = usize::MAX;
+
+/// An entry of the queue, linked to its older and newer neighbours.
+struct Node<K, V> {
+    key: K,
+    value: V,
+    visited: bool,
+    older: usize,
+    newer: usize,
+}
+
+/// Cache is an implementation of SIEVE holding up to a fixed number of entries.
+///
+/// Entries are kept in a single FIFO queue, and a hit sets the visited bit of its entry. To make
+/// room, a hand moves from the oldest entry towards the newest one, clearing the bit of every
+/// visited entry it passes, and evicts the first entry whose bit is already clear. Unlike CLOCK,
+/// new entries are inserted at the head of the queue rather than where the hand stands, so the
+/// hand keeps its position between evictions and wraps around to the oldest entry at the end.
+pub struct Cache<K, V> {
+    /// Arena of nodes, addressed by index. The node of a removed entry is empty.
+    nodes: Vec<Option<Node<K, V>>>,
+    /// Empty nodes.
+    free: Vec<usize>,
+    /// Index from the keys of the entries to their nodes.
+    index: HashMap<K, usize>,
+    /// Newest node of the queue.
+    head: usize,
+    /// Oldest node of the queue.
+    tail: usize,
+    /// Node the next eviction starts from, or `NIL` to start from the oldest node.
+    hand: usize,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates a new cache with the given maximum number of entries.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            head: NIL,
+            tail: NIL,
+            hand: NIL,
+            capacity: capacity.get(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node<K, V> {
+        self.nodes[index].as_mut().unwrap()
+    }
+
+    /// Links the given node at the head of the queue.
+    fn push_head(&mut self, index: usize) {
+        let head = self.head;
+        let node = self.node_mut(index);
+        node.older = head;
+        node.newer = NIL;
+        match head {
+            NIL => self.tail = index,
+            head => self.node_mut(head).newer = index,
+        }
+        self.head = index;
+    }
+
+    /// Unlinks the given node from the queue and takes it out of the arena, moving the hand to the
+    /// next newer node if it pointed at it.
+    fn unlink(&mut self, index: usize) -> Node<K, V> {
+        let node = self.nodes[index].take().unwrap();
+        match node.older {
+            NIL => self.tail = node.newer,
+            older => self.node_mut(older).newer = node.newer,
+        }
+        match node.newer {
+            NIL => self.head = node.older,
+            newer => self.node_mut(newer).older = node.older,
+        }
+        if self.hand == index {
+            self.hand = node.newer;
+        }
+        self.free.push(index);
+        node
+    }
+
+    /// Moves the hand until it finds an entry which was not visited since its last pass, and
+    /// evicts it. Must only be called when the queue is not empty.
+    fn evict(&mut self) {
+        let mut hand = if self.hand == NIL {
+            self.tail
+        } else {
+            self.hand
+        };
+        loop {
+            let node = self.node_mut(hand);
+            if !node.visited {
+                break;
+            }
+            node.visited = false;
+            hand = match node.newer {
+                NIL => self.tail,
+                newer => newer,
+            };
+        }
+        self.hand = hand;
+        let node = self.unlink(hand);
+        self.index.remove(&node.key);
+    }
+}
+
+impl<K, V> Policy<K, V> for Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let Some(&index) = self.index.get(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        let node = self.node_mut(index);
+        node.visited = true;
+        Some(&node.value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> bool {
+        if self.index.contains_key(&key) {
+            return false;
+        }
+        if self.index.len() >= self.capacity {
+            self.evict();
+        }
+        let node = Node {
+            key: key.clone(),
+            value,
+            visited: false,
+            older: NIL,
+            newer: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.push_head(index);
+        self.index.insert(key, index);
+        self.stats.insertions += 1;
+        true
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.index.remove(key)?;
+        Some(self.unlink(index).value)
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the keys of the queue from the oldest to the newest.
+    fn keys(cache: &Cache<u64, u64>) -> Vec<u64> {
+        let mut keys = Vec::new();
+        let mut index = cache.tail;
+        while index != NIL {
+            let node = cache.nodes[index].as_ref().unwrap();
+            keys.push(node.key);
+            index = node.newer;
+        }
+        keys
+    }
+
+    #[test]
+    fn test_hand_keeps_its_position() {
+        let mut cache = Cache::new(NonZeroUsize::new(4).unwrap());
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        cache.get(&0);
+        cache.get(&1);
+
+        // The hand skips 0 and 1, evicts 2 and stays on 3.
+        cache.insert(4, 4);
+        assert_eq!(keys(&cache), vec![0, 1, 3, 4]);
+
+        // Visited entries behind the hand are not looked at until it wraps around.
+        cache.get(&3);
+        cache.insert(5, 5);
+        assert_eq!(keys(&cache), vec![0, 1, 3, 5]);
+        cache.insert(6, 6);
+        assert_eq!(keys(&cache), vec![1, 3, 5, 6]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = Cache::new(NonZeroUsize::new(3).unwrap());
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        assert!(!cache.insert(0, 10));
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(keys(&cache), vec![0, 2]);
+
+        cache.insert(3, 3);
+        cache.insert(4, 4);
+        assert_eq!(keys(&cache), vec![2, 3, 4]);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&4), Some(&4));
+        assert_eq!(cache.stats().hits, 1);
+    }
+}
@@ -0,0 +1,246 @@
+/// Cache traces, read from the formats used by the literature on caching, and replayed through the
+/// policies of this module to compute their miss ratios.
+use hashbrown::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::storage::cache::Policy;
+
+/// Length of a record of the `oracleGeneral` format: a 32-bit timestamp, a 64-bit object id, a
+/// 32-bit object size and the 64-bit virtual time of the next access to the object.
+const ORACLE_GENERAL_RECORD_LEN: usize = 4 + 8 + 4 + 8;
+
+/// A single request of a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub key: u64,
+    /// Size of the requested object in bytes, or 1 if the format does not record sizes.
+    pub size: u64,
+}
+
+/// Format of a trace file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Text format of the ARC traces. Each line holds a starting block, a number of blocks and
+    /// optional fields which are ignored, and stands for a request to each block of the range.
+    Lis,
+    /// Text format with one `timestamp,key,size` request per line, and an optional header. Keys
+    /// which are not integers are hashed.
+    Csv,
+    /// Binary format of libCacheSim, made of little-endian fixed-size records.
+    OracleGeneral,
+}
+
+impl TraceFormat {
+    /// Guesses the format of a trace from its file name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        if name.contains("oracleGeneral") {
+            return Some(TraceFormat::OracleGeneral);
+        }
+        match path.extension()?.to_str()? {
+            "lis" => Some(TraceFormat::Lis),
+            "csv" => Some(TraceFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lis" => Ok(TraceFormat::Lis),
+            "csv" => Ok(TraceFormat::Csv),
+            "oracleGeneral" => Ok(TraceFormat::OracleGeneral),
+            _ => Err(format!("unknown trace format: {s}")),
+        }
+    }
+}
+
+/// Reads all the requests of the trace at the given path.
+pub fn read_trace(path: &Path, format: TraceFormat) -> io::Result<Vec<Request>> {
+    let reader = BufReader::new(File::open(path)?);
+    match format {
+        TraceFormat::Lis => read_lis(reader),
+        TraceFormat::Csv => read_csv(reader),
+        TraceFormat::OracleGeneral => read_oracle_general(reader),
+    }
+}
+
+fn read_lis<R: BufRead>(reader: R) -> io::Result<Vec<Request>> {
+    let mut requests = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_ascii_whitespace();
+        let Some(start) = fields.next() else {
+            continue;
+        };
+        let start = parse_field(start, i)?;
+        let count = match fields.next() {
+            Some(count) => parse_field(count, i)?,
+            None => 1,
+        };
+        requests.extend((start..start + count).map(|key| Request { key, size: 1 }));
+    }
+    Ok(requests)
+}
+
+fn read_csv<R: BufRead>(reader: R) -> io::Result<Vec<Request>> {
+    let mut requests = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [timestamp, key, size] = fields[..] else {
+            return Err(invalid_line(i, "expected timestamp,key,size"));
+        };
+        if i == 0 && timestamp.parse::<u64>().is_err() {
+            // Header.
+            continue;
+        }
+        let key = key.parse().unwrap_or_else(|_| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        });
+        let size = parse_field(size, i)?;
+        requests.push(Request { key, size });
+    }
+    Ok(requests)
+}
+
+fn read_oracle_general<R: Read>(mut reader: R) -> io::Result<Vec<Request>> {
+    let mut requests = Vec::new();
+    let mut record = [0; ORACLE_GENERAL_RECORD_LEN];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let key = u64::from_le_bytes(record[4..12].try_into().unwrap());
+        let size = u32::from_le_bytes(record[12..16].try_into().unwrap());
+        requests.push(Request {
+            key,
+            size: size as u64,
+        });
+    }
+    Ok(requests)
+}
+
+fn parse_field(field: &str, line: usize) -> io::Result<u64> {
+    field
+        .parse()
+        .map_err(|_| invalid_line(line, &format!("invalid number {field:?}")))
+}
+
+fn invalid_line(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {message}", line + 1),
+    )
+}
+
+/// Returns the number of distinct keys requested by the trace.
+pub fn unique_keys(requests: &[Request]) -> usize {
+    requests
+        .iter()
+        .map(|request| request.key)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Replays the trace through the given policy, inserting the keys which miss, and returns the
+/// number of misses.
+pub fn replay<P: Policy<u64, ()> + ?Sized>(policy: &mut P, requests: &[Request]) -> u64 {
+    let mut misses = 0;
+    for request in requests {
+        if policy.get(&request.key).is_none() {
+            misses += 1;
+            policy.insert(request.key, ());
+        }
+    }
+    misses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::cache::lru;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_read_lis() {
+        let trace = "10 3 0 1\n\n20 1 0 2\n10 1\n";
+        let requests = read_lis(trace.as_bytes()).unwrap();
+        let keys: Vec<u64> = requests.iter().map(|request| request.key).collect();
+        assert_eq!(keys, vec![10, 11, 12, 20, 10]);
+        assert_eq!(unique_keys(&requests), 4);
+
+        let err = read_lis("10 x\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let trace = "timestamp,key,size\n1,42,100\n2,page.html,200\n3,42,100\n";
+        let requests = read_csv(trace.as_bytes()).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], Request { key: 42, size: 100 });
+        assert_eq!(requests[1].size, 200);
+        assert_eq!(unique_keys(&requests), 2);
+
+        let err = read_csv("1,42\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_oracle_general() {
+        let mut trace = Vec::new();
+        for (key, size) in [(7u64, 512u32), (9, 1024), (7, 512)] {
+            trace.extend_from_slice(&1u32.to_le_bytes());
+            trace.extend_from_slice(&key.to_le_bytes());
+            trace.extend_from_slice(&size.to_le_bytes());
+            trace.extend_from_slice(&(-1i64).to_le_bytes());
+        }
+        // A truncated record at the end is ignored.
+        trace.extend_from_slice(&[0; 3]);
+
+        let requests = read_oracle_general(trace.as_slice()).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1], Request { key: 9, size: 1024 });
+        assert_eq!(unique_keys(&requests), 2);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            TraceFormat::from_path(Path::new("traces/OLTP.lis")),
+            Some(TraceFormat::Lis)
+        );
+        assert_eq!(
+            TraceFormat::from_path(Path::new("w01.oracleGeneral.bin")),
+            Some(TraceFormat::OracleGeneral)
+        );
+        assert_eq!(TraceFormat::from_path(Path::new("trace.txt")), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let requests: Vec<Request> = [1, 2, 1, 3, 1, 2]
+            .into_iter()
+            .map(|key| Request { key, size: 1 })
+            .collect();
+        let mut cache = lru::Cache::new(NonZeroUsize::new(2).unwrap());
+        // 2 is evicted by 3, and misses again.
+        assert_eq!(replay(&mut cache, &requests), 4);
+    }
+}
@@ -0,0 +1,269 @@
+/// Warm-start files, which persist the contents of an S3-FIFO cache so that it can be reloaded
+/// after a restart instead of starting cold.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crc32fast::Hasher as crc32Hasher;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of every warm-start file.
+const MAGIC: &[u8; 4] = b"S3WS";
+
+/// Version of the warm-start file format.
+const VERSION: u16 = 2;
+
+/// Length of the header: magic, version, tag, body length and checksum.
+const HEADER_LEN: usize = 4 + 2 + 8 + 8 + 4;
+
+/// Persist encodes and decodes the keys and values of a cache in warm-start files.
+pub trait Persist: Sized {
+    fn encode(&self, buf: &mut BytesMut);
+
+    /// Decodes a value from the front of `buf`, or returns `None` if it is truncated.
+    fn decode(buf: &mut Bytes) -> Option<Self>;
+}
+
+impl Persist for u64 {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(*self);
+    }
+
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        (buf.remaining() >= 8).then(|| buf.get_u64())
+    }
+}
+
+impl Persist for Bytes {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.len() as u64);
+        buf.put_slice(self);
+    }
+
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        let len = u64::decode(buf)?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= buf.remaining())?;
+        Some(buf.split_to(len))
+    }
+}
+
+impl Persist for Vec<u8> {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.len() as u64);
+        buf.put_slice(self);
+    }
+
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        Bytes::decode(buf).map(|bytes| bytes.to_vec())
+    }
+}
+
+/// A resident entry of a warm-start file: its key, value, frequency and refetch cost.
+pub(crate) type WarmEntry<K, V> = (K, V, u8, u64);
+
+/// WarmState is the persisted state of a cache: the resident entries of the small and main queues
+/// in queue order with their frequency and cost, and the fingerprints and weights of the ghost
+/// queue from oldest to newest.
+#[derive(Debug, PartialEq)]
+pub(crate) struct WarmState<K, V> {
+    pub(crate) small: Vec<WarmEntry<K, V>>,
+    pub(crate) main: Vec<WarmEntry<K, V>>,
+    pub(crate) ghost: Vec<(u32, u64)>,
+}
+
+impl<K, V> Default for WarmState<K, V> {
+    fn default() -> Self {
+        Self {
+            small: Vec::new(),
+            main: Vec::new(),
+            ghost: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> WarmState<K, V> {
+    /// Appends the given state to this one, queue by queue.
+    pub(crate) fn extend(&mut self, other: WarmState<K, V>) {
+        self.small.extend(other.small);
+        self.main.extend(other.main);
+        self.ghost.extend(other.ghost);
+    }
+}
+
+impl<K: Persist, V: Persist> WarmState<K, V> {
+    /// Writes the state to the given file, tagged with `tag`.
+    ///
+    /// The file is written next to its final path and renamed into place, so a crash never leaves
+    /// a partially written file behind.
+    pub(crate) fn save(&self, path: &Path, tag: u64) -> io::Result<()> {
+        let body = self.encode();
+        let mut hasher = crc32Hasher::new();
+        hasher.update(&body);
+
+        let mut header = BytesMut::with_capacity(HEADER_LEN);
+        header.put_slice(MAGIC);
+        header.put_u16(VERSION);
+        header.put_u64(tag);
+        header.put_u64(body.len() as u64);
+        header.put_u32(hasher.finalize());
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&header)?;
+        file.write_all(&body)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Reads the state from the given file.
+    ///
+    /// Fails with `InvalidData` if the file has another format version, was saved with another
+    /// tag, fails its checksum or is truncated.
+    pub(crate) fn load(path: &Path, tag: u64) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let mut data = Bytes::from(data);
+
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a warm-start file"));
+        }
+        data.advance(MAGIC.len());
+        let version = data.get_u16();
+        if version != VERSION {
+            return Err(invalid_data("unsupported warm-start file version"));
+        }
+        if data.get_u64() != tag {
+            return Err(invalid_data(
+                "warm-start file does not match the current state",
+            ));
+        }
+        let body_len = data.get_u64();
+        let checksum = data.get_u32();
+        if data.len() as u64 != body_len {
+            return Err(invalid_data("truncated warm-start file"));
+        }
+        let mut hasher = crc32Hasher::new();
+        hasher.update(&data);
+        if hasher.finalize() != checksum {
+            return Err(invalid_data("warm-start file checksum mismatch"));
+        }
+
+        Self::decode(&mut data).ok_or_else(|| invalid_data("corrupted warm-start file"))
+    }
+
+    fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for queue in [&self.small, &self.main] {
+            buf.put_u64(queue.len() as u64);
+            for (key, value, freq, cost) in queue {
+                key.encode(&mut buf);
+                value.encode(&mut buf);
+                buf.put_u8(*freq);
+                buf.put_u64(*cost);
+            }
+        }
+        buf.put_u64(self.ghost.len() as u64);
+        for (fingerprint, weight) in &self.ghost {
+            buf.put_u32(*fingerprint);
+            buf.put_u64(*weight);
+        }
+        buf
+    }
+
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        let small = Self::decode_queue(buf)?;
+        let main = Self::decode_queue(buf)?;
+        let ghost_len = u64::decode(buf)?;
+        let mut ghost = Vec::new();
+        for _ in 0..ghost_len {
+            if buf.remaining() < 12 {
+                return None;
+            }
+            ghost.push((buf.get_u32(), buf.get_u64()));
+        }
+        buf.is_empty().then_some(Self { small, main, ghost })
+    }
+
+    fn decode_queue(buf: &mut Bytes) -> Option<Vec<WarmEntry<K, V>>> {
+        let len = u64::decode(buf)?;
+        let mut queue = Vec::new();
+        for _ in 0..len {
+            let key = K::decode(buf)?;
+            let value = V::decode(buf)?;
+            if buf.remaining() < 9 {
+                return None;
+            }
+            queue.push((key, value, buf.get_u8(), buf.get_u64()));
+        }
+        Some(queue)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn state() -> WarmState<u64, Bytes> {
+        WarmState {
+            small: vec![(1, Bytes::from_static(b"one"), 0, 0)],
+            main: vec![
+                (2, Bytes::from_static(b"two"), 3, 4096),
+                (3, Bytes::from_static(b""), 1, 0),
+            ],
+            ghost: vec![(42, 1), (7, 3)],
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("warm_start").unwrap();
+        let path = dir.path().join("cache.warm");
+
+        state().save(&path, 10).unwrap();
+        assert_eq!(WarmState::load(&path, 10).unwrap(), state());
+    }
+
+    #[test]
+    fn tag_mismatch_is_rejected() {
+        let dir = TempDir::new("warm_start").unwrap();
+        let path = dir.path().join("cache.warm");
+
+        state().save(&path, 10).unwrap();
+        let err = WarmState::<u64, Bytes>::load(&path, 11).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let dir = TempDir::new("warm_start").unwrap();
+        let path = dir.path().join("cache.warm");
+        state().save(&path, 10).unwrap();
+        let data = fs::read(&path).unwrap();
+
+        // Flipped body byte.
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        fs::write(&path, &corrupted).unwrap();
+        assert!(WarmState::<u64, Bytes>::load(&path, 10).is_err());
+
+        // Truncated body.
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(WarmState::<u64, Bytes>::load(&path, 10).is_err());
+
+        // Unknown version.
+        let mut corrupted = data.clone();
+        corrupted[5] = VERSION as u8 + 1;
+        fs::write(&path, &corrupted).unwrap();
+        assert!(WarmState::<u64, Bytes>::load(&path, 10).is_err());
+
+        // Not a warm-start file.
+        fs::write(&path, b"garbage").unwrap();
+        assert!(WarmState::<u64, Bytes>::load(&path, 10).is_err());
+    }
+}
@@ -429,24 +429,21 @@ impl ValueRef {
 
     /// Resolves the value from the given offset in the commit log.
     /// If the offset exists in the value cache, it returns the cached value.
-    /// Otherwise, it reads the value from the commit log, caches it, and returns it.
+    /// Otherwise, it reads the value from the second tier of the value cache if enabled, or from
+    /// the commit log, caches it, and returns it.
     fn resolve_from_offset(&self, value_offset: u64) -> Result<Vec<u8>> {
-        // Check if the offset exists in value_cache and return if found
-        if let Some(value) = self.store.value_cache.get(&value_offset) {
-            return Ok(value.to_vec());
-        }
-
-        // Read the value from the commit log at the specified offset
-        let mut buf = vec![0; self.value_length];
-        let vlog = self.store.clog.read();
-        vlog.read_at(&mut buf, value_offset)?;
-
-        // Store the offset and value in value_cache
-        self.store
+        let value = self
+            .store
             .value_cache
-            .insert(value_offset, Bytes::from(buf.clone()));
-
-        Ok(buf)
+            .try_get_or_insert_with(value_offset, || {
+                // Read the value from the commit log at the specified offset
+                let mut buf = vec![0; self.value_length];
+                let vlog = self.store.clog.read();
+                vlog.read_at(&mut buf, value_offset)?;
+                Ok(Bytes::from(buf))
+            })?;
+
+        Ok(value.to_vec())
     }
 }
 
@@ -2,6 +2,7 @@ pub mod entry;
 pub mod error;
 pub(crate) mod indexer;
 pub(crate) mod meta;
+pub(crate) mod negative_cache;
 pub mod option;
 pub(crate) mod oracle;
 pub(crate) mod reader;
@@ -10,3 +11,4 @@ pub mod snapshot;
 pub mod store;
 pub mod transaction;
 pub(crate) mod util;
+pub(crate) mod value_cache;
@@ -0,0 +1,169 @@
+use std::hash::BuildHasher;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use hashbrown::hash_map::DefaultHashBuilder;
+use parking_lot::RwLock;
+
+use crate::storage::cache::s3fifo::{Cache, CacheStats};
+
+/// A key found absent from the index.
+#[derive(Clone, Debug)]
+struct Absent {
+    /// The key itself, to tell it apart from other keys with the same hash.
+    key: Bytes,
+    /// Epoch of the snapshot which found the key absent.
+    epoch: u64,
+}
+
+/// Cache of the keys found absent by transaction point reads, keyed by their hash and evicted
+/// with S3-FIFO, so that reading them again skips the index lookup.
+///
+/// Every write to the index bumps the epoch of the cache and drops the written keys, and every
+/// snapshot records the epoch it was taken at. A key is only cached if the index has not been
+/// written since the snapshot which found it absent, and is only reported absent to snapshots
+/// at least as recent, as older ones may still see a version of the key which was deleted since.
+pub(crate) struct NegativeCache {
+    cache: RwLock<Cache<u64, Absent>>,
+    hash_builder: DefaultHashBuilder,
+    epoch: AtomicU64,
+    /// Lookups are counted here rather than by the cache, as entries found in the cache may
+    /// still be too recent for the snapshot.
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NegativeCache {
+    /// Creates a new negative cache holding up to `capacity` keys.
+    pub(crate) fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            cache: RwLock::new(Cache::new(capacity)),
+            hash_builder: DefaultHashBuilder::default(),
+            epoch: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current epoch. Snapshots read it while holding the lock of the indexer, so
+    /// that it matches the state of the index they see.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the key is known to be absent from a snapshot taken at the given epoch.
+    pub(crate) fn contains(&self, key: &[u8], epoch: u64) -> bool {
+        let hash = self.hash_builder.hash_one(key);
+        let found = self
+            .cache
+            .read()
+            .get(&hash)
+            .is_some_and(|absent| absent.key == key && absent.epoch <= epoch);
+        if found {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Records that the key is absent from a snapshot taken at the given epoch. Nothing is
+    /// recorded if the index was written since, as the key may have been written too.
+    pub(crate) fn insert(&self, key: Bytes, epoch: u64) {
+        let hash = self.hash_builder.hash_one(&key[..]);
+        let mut cache = self.cache.write();
+        if self.epoch.load(Ordering::Acquire) == epoch {
+            cache.insert_or_update(hash, Absent { key, epoch });
+        }
+    }
+
+    /// Bumps the epoch and forgets the given keys, which are about to be written to the index.
+    /// Must be called while holding the write lock of the indexer.
+    pub(crate) fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) {
+        let mut cache = self.cache.write();
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        for key in keys {
+            cache.remove(&self.hash_builder.hash_one(key));
+        }
+    }
+
+    /// Returns a snapshot of the statistics of the cache.
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..self.cache.read().stats()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize) -> NegativeCache {
+        NegativeCache::new(NonZeroUsize::new(capacity).unwrap())
+    }
+
+    #[test]
+    fn test_absent_keys_are_cached() {
+        let cache = cache(10);
+        let epoch = cache.epoch();
+        assert!(!cache.contains(b"foo", epoch));
+
+        cache.insert(Bytes::from_static(b"foo"), epoch);
+        assert!(cache.contains(b"foo", epoch));
+        assert!(!cache.contains(b"bar", epoch));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+    }
+
+    #[test]
+    fn test_writes_invalidate_keys() {
+        let cache = cache(10);
+        let epoch = cache.epoch();
+        cache.insert(Bytes::from_static(b"foo"), epoch);
+        cache.insert(Bytes::from_static(b"bar"), epoch);
+
+        cache.invalidate([&b"foo"[..]]);
+        let epoch = cache.epoch();
+        assert!(!cache.contains(b"foo", epoch));
+        // Keys which were not written stay cached for newer snapshots.
+        assert!(cache.contains(b"bar", epoch));
+    }
+
+    #[test]
+    fn test_stale_snapshots() {
+        let cache = cache(10);
+        let old_epoch = cache.epoch();
+        cache.invalidate([&b"bar"[..]]);
+
+        // The index was written since the snapshot was taken, so its view may be outdated.
+        cache.insert(Bytes::from_static(b"foo"), old_epoch);
+        assert!(!cache.contains(b"foo", cache.epoch()));
+
+        // Keys cached from a newer snapshot are not reported absent to older ones, which may
+        // still see a version of the key deleted since.
+        let epoch = cache.epoch();
+        cache.insert(Bytes::from_static(b"foo"), epoch);
+        assert!(cache.contains(b"foo", epoch));
+        assert!(!cache.contains(b"foo", old_epoch));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+    }
+
+    #[test]
+    fn test_capacity_is_bounded() {
+        let cache = cache(10);
+        let epoch = cache.epoch();
+        for i in 0..100u32 {
+            cache.insert(Bytes::from(i.to_be_bytes().to_vec()), epoch);
+        }
+        assert_eq!(cache.cache.read().len(), 10);
+    }
+}
@@ -13,6 +13,11 @@ const META_KEY_MAX_VALUE_THRESHOLD: &str = "max_value_threshold";
 const META_KEY_MAX_ENTRIES_PER_TX: &str = "max_entries_per_txn";
 const META_KEY_MAX_FILE_SIZE: &str = "max_file_size";
 const META_KEY_MAX_VALUE_CACHE_SIZE: &str = "max_value_cache_size";
+const META_KEY_MAX_VALUE_CACHE_BYTES: &str = "max_value_cache_bytes";
+const META_KEY_VALUE_CACHE_POLICY: &str = "value_cache_policy";
+const META_KEY_VALUE_CACHE_WARM_START: &str = "value_cache_warm_start";
+const META_KEY_VALUE_CACHE_DISK_SIZE: &str = "value_cache_disk_size";
+const META_KEY_NEGATIVE_CACHE_SIZE: &str = "negative_cache_size";
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum IsolationLevel {
@@ -30,6 +35,31 @@ impl IsolationLevel {
     }
 }
 
+/// Eviction policy used by the value cache of the store.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ValueCachePolicy {
+    QuickCache = 1,
+    S3Fifo = 2,
+    Lru = 3,
+    Clock = 4,
+    Sieve = 5,
+    Arc = 6,
+}
+
+impl ValueCachePolicy {
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            1 => Some(ValueCachePolicy::QuickCache),
+            2 => Some(ValueCachePolicy::S3Fifo),
+            3 => Some(ValueCachePolicy::Lru),
+            4 => Some(ValueCachePolicy::Clock),
+            5 => Some(ValueCachePolicy::Sieve),
+            6 => Some(ValueCachePolicy::Arc),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Options {
     // Required options.
@@ -44,7 +74,18 @@ pub struct Options {
     pub max_value_threshold: usize, // Threshold to decide value should be stored and read from memory or from log value files.
     pub max_entries_per_txn: u32,   // Maximum entries in a transaction.
     pub max_segment_size: u64,      // Maximum size of a single segment.
-    pub max_value_cache_size: u64,  // Maximum size of the value cache.
+    pub max_value_cache_size: u64,  // Maximum number of entries in the value cache.
+    /// Maximum total size in bytes of the values in the value cache, used by the S3-FIFO policy
+    /// instead of `max_value_cache_size`.
+    pub max_value_cache_bytes: u64,
+    /// Eviction policy of the value cache.
+    pub cache_policy: ValueCachePolicy,
+    /// Persist the S3-FIFO value cache on close and reload it on open.
+    pub value_cache_warm_start: bool,
+    /// Size in bytes of the on-disk second tier of the S3-FIFO value cache, 0 to disable it.
+    pub value_cache_disk_size: u64,
+    /// Number of absent keys remembered by transaction point reads, 0 to disable it.
+    pub negative_cache_size: u64,
 }
 
 impl Default for Options {
@@ -59,6 +100,11 @@ impl Default for Options {
             isolation_level: IsolationLevel::SnapshotIsolation,
             max_segment_size: 1 << 29, // 512 MB
             max_value_cache_size: 100000,
+            max_value_cache_bytes: 1 << 26, // 64 MB
+            cache_policy: ValueCachePolicy::QuickCache,
+            value_cache_warm_start: false,
+            value_cache_disk_size: 0,
+            negative_cache_size: 0,
         }
     }
 }
@@ -82,6 +128,14 @@ impl Options {
         metadata.put_uint(META_KEY_MAX_ENTRIES_PER_TX, self.max_entries_per_txn as u64);
         metadata.put_uint(META_KEY_MAX_FILE_SIZE, self.max_segment_size);
         metadata.put_uint(META_KEY_MAX_VALUE_CACHE_SIZE, self.max_value_cache_size);
+        metadata.put_uint(META_KEY_MAX_VALUE_CACHE_BYTES, self.max_value_cache_bytes);
+        metadata.put_uint(META_KEY_VALUE_CACHE_POLICY, self.cache_policy as u64);
+        metadata.put_uint(
+            META_KEY_VALUE_CACHE_WARM_START,
+            self.value_cache_warm_start as u64,
+        );
+        metadata.put_uint(META_KEY_VALUE_CACHE_DISK_SIZE, self.value_cache_disk_size);
+        metadata.put_uint(META_KEY_NEGATIVE_CACHE_SIZE, self.negative_cache_size);
 
         metadata
     }
@@ -91,6 +145,9 @@ impl Options {
         let isolation_level =
             IsolationLevel::from_u64(metadata.get_uint(META_KEY_ISOLATION_LEVEL)?)
                 .ok_or(Error::CorruptedMetadata)?;
+        let cache_policy =
+            ValueCachePolicy::from_u64(metadata.get_uint(META_KEY_VALUE_CACHE_POLICY)?)
+                .ok_or(Error::CorruptedMetadata)?;
 
         Ok(Options {
             dir,
@@ -101,6 +158,11 @@ impl Options {
             max_entries_per_txn: metadata.get_uint(META_KEY_MAX_ENTRIES_PER_TX)? as u32,
             max_segment_size: metadata.get_uint(META_KEY_MAX_FILE_SIZE)?,
             max_value_cache_size: metadata.get_uint(META_KEY_MAX_VALUE_CACHE_SIZE)?,
+            max_value_cache_bytes: metadata.get_uint(META_KEY_MAX_VALUE_CACHE_BYTES)?,
+            cache_policy,
+            value_cache_warm_start: metadata.get_uint(META_KEY_VALUE_CACHE_WARM_START)? != 0,
+            value_cache_disk_size: metadata.get_uint(META_KEY_VALUE_CACHE_DISK_SIZE)?,
+            negative_cache_size: metadata.get_uint(META_KEY_NEGATIVE_CACHE_SIZE)?,
         })
     }
 }
@@ -123,6 +185,11 @@ mod tests {
         assert_eq!(options.isolation_level, IsolationLevel::SnapshotIsolation);
         assert_eq!(options.max_segment_size, 1 << 29);
         assert_eq!(options.max_value_cache_size, 100000);
+        assert_eq!(options.max_value_cache_bytes, 1 << 26);
+        assert_eq!(options.cache_policy, ValueCachePolicy::QuickCache);
+        assert!(!options.value_cache_warm_start);
+        assert_eq!(options.value_cache_disk_size, 0);
+        assert_eq!(options.negative_cache_size, 0);
     }
 
     #[test]
@@ -136,6 +203,11 @@ mod tests {
             isolation_level: IsolationLevel::SerializableSnapshotIsolation,
             max_segment_size: 1 << 25, // 32 MB
             max_value_cache_size: 200000,
+            max_value_cache_bytes: 1 << 20,
+            cache_policy: ValueCachePolicy::S3Fifo,
+            value_cache_warm_start: true,
+            value_cache_disk_size: 1 << 30,
+            negative_cache_size: 10000,
         };
 
         let metadata = options.to_metadata();
@@ -156,6 +228,26 @@ mod tests {
             metadata.get_uint(META_KEY_MAX_VALUE_CACHE_SIZE).unwrap(),
             200000
         );
+        assert_eq!(
+            metadata.get_uint(META_KEY_MAX_VALUE_CACHE_BYTES).unwrap(),
+            1 << 20
+        );
+        assert_eq!(
+            metadata.get_uint(META_KEY_VALUE_CACHE_POLICY).unwrap(),
+            ValueCachePolicy::S3Fifo as u64
+        );
+        assert_eq!(
+            metadata.get_uint(META_KEY_VALUE_CACHE_WARM_START).unwrap(),
+            1
+        );
+        assert_eq!(
+            metadata.get_uint(META_KEY_VALUE_CACHE_DISK_SIZE).unwrap(),
+            1 << 30
+        );
+        assert_eq!(
+            metadata.get_uint(META_KEY_NEGATIVE_CACHE_SIZE).unwrap(),
+            10000
+        );
     }
 
     #[test]
@@ -171,6 +263,11 @@ mod tests {
         metadata.put_uint(META_KEY_MAX_ENTRIES_PER_TX, 500);
         metadata.put_uint(META_KEY_MAX_FILE_SIZE, 1 << 25);
         metadata.put_uint(META_KEY_MAX_VALUE_CACHE_SIZE, 200000);
+        metadata.put_uint(META_KEY_MAX_VALUE_CACHE_BYTES, 1 << 20);
+        metadata.put_uint(META_KEY_VALUE_CACHE_POLICY, ValueCachePolicy::Lru as u64);
+        metadata.put_uint(META_KEY_VALUE_CACHE_WARM_START, 1);
+        metadata.put_uint(META_KEY_VALUE_CACHE_DISK_SIZE, 1 << 30);
+        metadata.put_uint(META_KEY_NEGATIVE_CACHE_SIZE, 10000);
 
         let dir = PathBuf::from("/test/dir");
         let options_result = Options::from_metadata(metadata, dir.clone());
@@ -190,5 +287,19 @@ mod tests {
         );
         assert_eq!(options.max_segment_size, 1 << 25);
         assert_eq!(options.max_value_cache_size, 200000);
+        assert_eq!(options.max_value_cache_bytes, 1 << 20);
+        assert_eq!(options.cache_policy, ValueCachePolicy::Lru);
+        assert!(options.value_cache_warm_start);
+        assert_eq!(options.value_cache_disk_size, 1 << 30);
+        assert_eq!(options.negative_cache_size, 10000);
+    }
+
+    #[test]
+    fn options_from_metadata_invalid_cache_policy() {
+        let mut metadata = Options::default().to_metadata();
+        metadata.put_uint(META_KEY_VALUE_CACHE_POLICY, 42);
+
+        let options_result = Options::from_metadata(metadata, PathBuf::from("/test/dir"));
+        assert!(matches!(options_result, Err(Error::CorruptedMetadata)));
     }
 }
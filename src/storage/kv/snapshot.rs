@@ -20,21 +20,37 @@ pub(crate) struct Snapshot {
     /// key-value pairs in the snapshot. It can be used to filter out expired key-value
     /// pairs or deleted key-value pairs based on the read timestamp.
     ts: u64,
+    /// The epoch of the negative cache when the snapshot was taken, which tells the absent keys
+    /// of the cache that this snapshot cannot see.
+    epoch: u64,
     snap: TartSnapshot<VariableSizeKey, Bytes>,
     store: Arc<Core>,
 }
 
 impl Snapshot {
     pub(crate) fn take(store: Arc<Core>, ts: u64) -> Result<Self> {
-        let snapshot = store.indexer.write().snapshot()?;
+        let indexer = store.indexer.write();
+        let snapshot = indexer.snapshot()?;
+        // Read the epoch under the lock of the indexer, so that it matches the snapshot.
+        let epoch = store
+            .negative_cache
+            .as_ref()
+            .map_or(0, |negative_cache| negative_cache.epoch());
+        drop(indexer);
 
         Ok(Self {
             ts,
+            epoch,
             snap: snapshot,
             store,
         })
     }
 
+    /// Returns the epoch of the negative cache when the snapshot was taken.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
     /// Set a key-value pair into the snapshot.
     pub fn set(&mut self, key: &VariableSizeKey, value: Bytes) -> Result<()> {
         // TODO: need to fix this to avoid cloning the key
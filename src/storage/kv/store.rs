@@ -1,3 +1,5 @@
+use std::fs;
+use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::vec;
@@ -9,19 +11,24 @@ use tokio::task::{spawn, JoinHandle};
 use bytes::{Bytes, BytesMut};
 use hashbrown::HashMap;
 use parking_lot::RwLock;
-use quick_cache::sync::Cache;
 use tokio::sync::Mutex as AsyncMutex;
 use vart::art::KV;
 
 use crate::storage::{
+    cache::{
+        disk::{DiskCache, DiskCacheStats},
+        s3fifo::CacheStats,
+    },
     kv::{
         entry::{Entry, TxRecord, ValueRef},
         error::{Error, Result},
         indexer::Indexer,
-        option::Options,
+        negative_cache::NegativeCache,
+        option::{Options, ValueCachePolicy},
         oracle::Oracle,
         reader::{Reader, TxReader},
         transaction::{Mode, Transaction},
+        value_cache::ValueCache,
     },
     log::{
         aof::log::Aol,
@@ -155,6 +162,50 @@ impl Store {
 
         Ok(())
     }
+
+    /// Returns the statistics of the value cache.
+    /// Statistics are tracked by every policy of the value cache but quick_cache.
+    pub fn value_cache_stats(&self) -> Option<CacheStats> {
+        self.inner.as_ref().unwrap().core.value_cache.stats()
+    }
+
+    /// Returns the statistics of the on-disk second tier of the value cache, if it is enabled
+    /// through `Options::value_cache_disk_size`. Its hits are reads saved from the commit log
+    /// after a miss in the value cache.
+    pub fn value_cache_disk_stats(&self) -> Option<DiskCacheStats> {
+        let core = &self.inner.as_ref().unwrap().core;
+        core.value_cache_disk
+            .as_ref()
+            .map(|disk_cache| disk_cache.stats())
+    }
+
+    /// Starts estimating the miss-ratio curve of the value cache from live traffic, following at
+    /// most `max_samples` keys. The curve is reported by `value_cache_stats`, as the hit ratio the
+    /// cache would have at a few multiples of `Options::max_value_cache_bytes`.
+    /// Only the S3-FIFO policy supports the estimation.
+    pub fn enable_value_cache_mrc(&self, max_samples: usize) {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .core
+            .value_cache
+            .enable_mrc(max_samples);
+    }
+
+    /// Stops estimating the miss-ratio curve of the value cache.
+    pub fn disable_value_cache_mrc(&self) {
+        self.inner.as_ref().unwrap().core.value_cache.disable_mrc();
+    }
+
+    /// Returns the statistics of the negative cache of transaction point reads, if it is enabled
+    /// through `Options::negative_cache_size`. Its hits are reads of missing keys which skipped
+    /// the index lookup.
+    pub fn negative_cache_stats(&self) -> Option<CacheStats> {
+        let core = &self.inner.as_ref().unwrap().core;
+        core.negative_cache
+            .as_ref()
+            .map(|negative_cache| negative_cache.stats())
+    }
 }
 
 impl Drop for Store {
@@ -215,6 +266,12 @@ impl TaskRunner {
     }
 }
 
+/// Name of the file the value cache is saved to on close, when warm starts are enabled.
+const VALUE_CACHE_WARM_START_FILE: &str = "value_cache.warm";
+
+/// Name of the file of the on-disk second tier of the value cache, when enabled.
+const VALUE_CACHE_DISK_FILE: &str = "value_cache.disk";
+
 /// Core of the key-value store.
 pub struct Core {
     /// Index for store.
@@ -230,8 +287,15 @@ pub struct Core {
     /// Value cache for store.
     /// The assumption for this cache is that it should be useful for
     /// storing offsets that are frequently accessed (especially in
-    /// the case of range scans)
-    pub(crate) value_cache: Cache<u64, Bytes>,
+    /// the case of range scans). The eviction policy is selected
+    /// through `Options::cache_policy`.
+    pub(crate) value_cache: ValueCache,
+    /// Second tier of the value cache, holding the values evicted from the main queue of the
+    /// S3-FIFO policy in a local file, checked before reading from the commit log.
+    pub(crate) value_cache_disk: Option<Arc<DiskCache<u64>>>,
+    /// Keys found absent by transaction point reads, invalidated when the keys are written to
+    /// the index.
+    pub(crate) negative_cache: Option<NegativeCache>,
     /// Flag to indicate if the store is closed.
     is_closed: AtomicBool,
     /// Channel to send write requests to the writer
@@ -289,8 +353,43 @@ impl Core {
         let oracle = Oracle::new(&opts);
         oracle.set_ts(indexer.version());
 
-        // Create and initialize value cache.
-        let value_cache = Cache::new(opts.max_value_cache_size as usize);
+        // Create the second tier of the value cache if enabled. It only keeps values evicted
+        // from the main queue of the S3-FIFO policy, so the other policies do not use it.
+        let value_cache_disk =
+            if opts.value_cache_disk_size > 0 && opts.cache_policy == ValueCachePolicy::S3Fifo {
+                let path = opts.dir.join(VALUE_CACHE_DISK_FILE);
+                Some(Arc::new(DiskCache::open(
+                    &path,
+                    opts.value_cache_disk_size,
+                )?))
+            } else {
+                None
+            };
+
+        // Create and initialize value cache, reloading it from its warm-start file if enabled.
+        // The file is tagged with the size of the commit log, so that it is only loaded if the
+        // log has not changed since the cache was saved.
+        let value_cache = match &value_cache_disk {
+            Some(disk_cache) => ValueCache::with_disk_cache(&opts, disk_cache.clone()),
+            None => ValueCache::new(&opts),
+        };
+        if opts.value_cache_warm_start {
+            let warm_start_path = opts.dir.join(VALUE_CACHE_WARM_START_FILE);
+            if warm_start_path.exists() {
+                if let Err(err) = value_cache.load_warm_start(&warm_start_path, clog.size()?) {
+                    // TODO: use log/tracing instead of eprintln
+                    eprintln!("Ignoring value cache warm-start file: {}", err);
+                }
+                if let Err(err) = fs::remove_file(&warm_start_path) {
+                    // TODO: use log/tracing instead of eprintln
+                    eprintln!("Failed to remove value cache warm-start file: {}", err);
+                }
+            }
+        }
+
+        // Create the negative cache of transaction point reads if enabled.
+        let negative_cache =
+            NonZeroUsize::new(opts.negative_cache_size as usize).map(NegativeCache::new);
 
         // Construct and return the Core instance.
         Ok(Self {
@@ -300,6 +399,8 @@ impl Core {
             clog: Arc::new(RwLock::new(clog)),
             oracle: Arc::new(oracle),
             value_cache,
+            value_cache_disk,
+            negative_cache,
             is_closed: AtomicBool::new(false),
             writes_tx,
         })
@@ -442,6 +543,30 @@ impl Core {
         // Close the indexer
         self.indexer.write().close()?;
 
+        // Save the value cache for the next start. A failure only costs a cold cache, so it does
+        // not fail the close.
+        if self.opts.value_cache_warm_start {
+            let warm_start_path = self.opts.dir.join(VALUE_CACHE_WARM_START_FILE);
+            // The offset includes data not yet flushed, which closing the log writes out.
+            let tag = self.clog.read().offset()?;
+            if let Err(err) = self.value_cache.save_warm_start(&warm_start_path, tag) {
+                // TODO: use log/tracing instead of eprintln
+                eprintln!("Error occurred while saving the value cache: {}", err);
+            }
+        }
+
+        // The second tier of the value cache does not survive restarts.
+        if self.value_cache_disk.is_some() {
+            let disk_cache_path = self.opts.dir.join(VALUE_CACHE_DISK_FILE);
+            if let Err(err) = fs::remove_file(disk_cache_path) {
+                // TODO: use log/tracing instead of eprintln
+                eprintln!(
+                    "Error occurred while removing the value cache file: {}",
+                    err
+                );
+            }
+        }
+
         // Close the commit log
         self.clog.write().close()?;
 
@@ -499,6 +624,12 @@ impl Core {
         let mut index = self.indexer.write();
         let mut kv_pairs = Vec::new();
 
+        // Forget the written keys while holding the lock of the indexer, so that no snapshot
+        // sees the new state of the index alongside stale entries.
+        if let Some(negative_cache) = &self.negative_cache {
+            negative_cache.invalidate(req.entries.iter().map(|entry| &entry.key[..]));
+        }
+
         for entry in &req.entries {
             let index_value = ValueRef::encode(
                 &entry.key,
@@ -543,8 +674,9 @@ impl Core {
 mod tests {
     use std::sync::Arc;
 
-    use crate::storage::kv::option::Options;
-    use crate::storage::kv::store::{Store, Task, TaskRunner};
+    use crate::storage::cache::sharded::default_shard_count;
+    use crate::storage::kv::option::{Options, ValueCachePolicy};
+    use crate::storage::kv::store::{Store, Task, TaskRunner, VALUE_CACHE_DISK_FILE};
 
     use async_channel::bounded;
     use std::sync::atomic::{AtomicU64, Ordering};
@@ -962,4 +1094,250 @@ mod tests {
             "should close store without error"
         );
     }
+
+    #[tokio::test]
+    async fn value_cache_stats() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        // Create store options with the test directory, storing all values in the commit log
+        // so that reads go through the value cache
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.max_value_threshold = 0;
+        opts.cache_policy = ValueCachePolicy::S3Fifo;
+
+        let store = Store::new(opts).expect("should create store");
+
+        let key = Bytes::from("key");
+        let value = Bytes::from("value");
+
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, &value).unwrap();
+        txn.commit().await.unwrap();
+
+        // The first read misses the value cache, the second one hits it
+        for _ in 0..2 {
+            let txn = store.begin().unwrap();
+            let val = txn.get(&key).unwrap().unwrap();
+            assert_eq!(val, value.as_ref());
+        }
+
+        let stats = store.value_cache_stats().unwrap();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.bytes_saved, value.len() as u64);
+        assert!(stats.mrc.is_none());
+
+        store.enable_value_cache_mrc(1024);
+        for _ in 0..2 {
+            let txn = store.begin().unwrap();
+            txn.get(&key).unwrap().unwrap();
+        }
+        // The estimator sees the key for the first time on the first read
+        let mrc = store.value_cache_stats().unwrap().mrc.unwrap();
+        assert_eq!(mrc.lookups, 2);
+        assert_eq!(mrc.hit_ratio(1.0), Some(0.5));
+    }
+
+    #[tokio::test]
+    async fn value_cache_holds_values_larger_than_a_default_shard() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.max_value_threshold = 0;
+        opts.cache_policy = ValueCachePolicy::S3Fifo;
+        opts.max_value_cache_bytes = 100000;
+
+        // The value would not fit in a shard if the budget was split over the default number of
+        // shards.
+        let value = Bytes::from(vec![1; 1 << 16]);
+        let default_shard_weight = opts.max_value_cache_bytes / default_shard_count().get() as u64;
+        assert!(value.len() as u64 > default_shard_weight);
+
+        let store = Store::new(opts).expect("should create store");
+        let key = Bytes::from("key");
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, &value).unwrap();
+        txn.commit().await.unwrap();
+
+        for _ in 0..2 {
+            let txn = store.begin().unwrap();
+            assert_eq!(txn.get(&key).unwrap().unwrap(), value.as_ref());
+        }
+
+        let stats = store.value_cache_stats().unwrap();
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.hits, 1);
+    }
+
+    #[tokio::test]
+    async fn value_cache_disk_tier() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.max_value_threshold = 0;
+        opts.max_value_cache_bytes = 1 << 16;
+        opts.cache_policy = ValueCachePolicy::S3Fifo;
+        opts.value_cache_disk_size = 1 << 20;
+
+        let store = Store::new(opts).expect("should create store");
+        assert!(store.value_cache_disk_stats().is_some());
+
+        let keys: Vec<Bytes> = (0..1 << 14)
+            .map(|i| Bytes::from(format!("{:08}", i)))
+            .collect();
+        for chunk in keys.chunks(1 << 10) {
+            let mut txn = store.begin().unwrap();
+            for key in chunk {
+                txn.set(key, key).unwrap();
+            }
+            txn.commit().await.unwrap();
+        }
+
+        // Reading every value twice promotes it into the main queue of the value cache, which
+        // evicts the oldest values to the disk cache once it is full. Reading them again finds
+        // them on disk.
+        for _ in 0..3 {
+            let txn = store.begin().unwrap();
+            for key in &keys {
+                assert_eq!(txn.get(key).unwrap().unwrap(), key.as_ref());
+            }
+        }
+
+        let stats = store.value_cache_stats().unwrap();
+        let disk_stats = store.value_cache_disk_stats().unwrap();
+        assert!(disk_stats.hits > 0);
+        assert_eq!(disk_stats.hits + disk_stats.misses, stats.misses);
+        assert_eq!(disk_stats.insertions, stats.main_evictions);
+
+        store.close().await.unwrap();
+        assert!(!temp_dir.path().join(VALUE_CACHE_DISK_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn value_cache_misses_on_one_offset_read_it_once() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.max_value_threshold = 0;
+        opts.cache_policy = ValueCachePolicy::S3Fifo;
+        // Every read of a value missing from the value cache looks it up in the disk cache first,
+        // so its lookups count the reads.
+        opts.value_cache_disk_size = 1 << 20;
+
+        let store = Store::new(opts).expect("should create store");
+        let key = Bytes::from("key");
+        let value = Bytes::from(vec![1; 1 << 16]);
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, &value).unwrap();
+        txn.commit().await.unwrap();
+
+        let readers = 16;
+        let barrier = std::sync::Barrier::new(readers);
+        std::thread::scope(|scope| {
+            for _ in 0..readers {
+                scope.spawn(|| {
+                    let txn = store.begin().unwrap();
+                    barrier.wait();
+                    assert_eq!(txn.get(&key).unwrap().unwrap(), value.as_ref());
+                });
+            }
+        });
+
+        let disk_stats = store.value_cache_disk_stats().unwrap();
+        assert_eq!(disk_stats.hits + disk_stats.misses, 1);
+        assert_eq!(store.value_cache_stats().unwrap().insertions, 1);
+    }
+
+    #[tokio::test]
+    async fn negative_cache() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.negative_cache_size = 100;
+
+        let store = Store::new(opts).expect("should create store");
+        let key = Bytes::from("foo");
+
+        // The second read of a missing key is answered by the negative cache.
+        let txn = store.begin().unwrap();
+        assert!(txn.get(&key).unwrap().is_none());
+        assert!(txn.get(&key).unwrap().is_none());
+        let stats = store.negative_cache_stats().unwrap();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+
+        // Writing the key invalidates it.
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, &key).unwrap();
+        txn.commit().await.unwrap();
+        assert!(txn_get(&store, &key).is_some());
+
+        let old_txn = store.begin().unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.delete(&key).unwrap();
+        txn.commit().await.unwrap();
+        assert!(txn_get(&store, &key).is_none());
+        assert!(txn_get(&store, &key).is_none());
+        // The key is cached as absent again, but not for snapshots taken before its deletion.
+        assert_eq!(old_txn.get(&key).unwrap().unwrap(), key.as_ref());
+
+        // Transactions read their own writes of keys cached as absent.
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, b"bar").unwrap();
+        assert_eq!(txn.get(&key).unwrap().unwrap(), b"bar");
+
+        let stats = store.negative_cache_stats().unwrap();
+        assert_eq!(stats.hits, 2);
+
+        store.close().await.unwrap();
+    }
+
+    fn txn_get(store: &Store, key: &[u8]) -> Option<Vec<u8>> {
+        store.begin().unwrap().get(key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn value_cache_warm_start() {
+        // Create a temporary directory for testing
+        let temp_dir = create_temp_directory();
+
+        let mut opts = Options::new();
+        opts.dir = temp_dir.path().to_path_buf();
+        opts.max_value_threshold = 0;
+        opts.cache_policy = ValueCachePolicy::S3Fifo;
+        opts.value_cache_warm_start = true;
+
+        let key = Bytes::from("key");
+        let value = Bytes::from("value");
+
+        let store = Store::new(opts.clone()).expect("should create store");
+        let mut txn = store.begin().unwrap();
+        txn.set(&key, &value).unwrap();
+        txn.commit().await.unwrap();
+
+        // Read the value once so that it is cached, then close the store to save the cache
+        let txn = store.begin().unwrap();
+        assert_eq!(txn.get(&key).unwrap().unwrap(), value.as_ref());
+        store.close().await.unwrap();
+
+        // The reopened store starts with the cached value
+        let store = Store::new(opts).expect("should reopen store");
+        let txn = store.begin().unwrap();
+        assert_eq!(txn.get(&key).unwrap().unwrap(), value.as_ref());
+
+        let stats = store.value_cache_stats().unwrap();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 0);
+    }
 }
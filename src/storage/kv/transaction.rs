@@ -156,6 +156,23 @@ impl Transaction {
         let key = Bytes::copy_from_slice(key);
         let hashed_key = sha256(key.clone());
 
+        // Keys written by the transaction are in its snapshot but not in the index, so the
+        // negative cache only answers for the others.
+        let negative_cache = self
+            .core
+            .negative_cache
+            .as_ref()
+            .filter(|_| !self.write_order_map.contains_key(&hashed_key));
+        let epoch = self.snapshot.read().epoch();
+        if let Some(negative_cache) = negative_cache {
+            if negative_cache.contains(&key, epoch) {
+                if !self.mode.is_read_only() {
+                    self.read_set.lock().push((key, 0));
+                }
+                return Ok(None);
+            }
+        }
+
         // Attempt to get the value for the key from the snapshot.
         match self.snapshot.read().get(&key[..].into()) {
             Ok(val_ref) => {
@@ -186,6 +203,9 @@ impl Transaction {
                     // add the key to the read set with a timestamp of 0.
                     Error::IndexError(trie_error) => {
                         if let TrieError::KeyNotFound = trie_error {
+                            if let Some(negative_cache) = negative_cache {
+                                negative_cache.insert(key.clone(), epoch);
+                            }
                             if !self.mode.is_read_only() {
                                 self.read_set.lock().push((key, 0));
                             }
@@ -0,0 +1,469 @@
+use std::cmp::min;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quick_cache::sync::Cache as QuickCache;
+
+use crate::storage::{
+    cache::{
+        arc, clock,
+        disk::DiskCache,
+        lru,
+        s3fifo::{CacheStats, EvictionCause, Weigher},
+        sharded::{default_shard_count, ShardedCache},
+        sieve, Policy,
+    },
+    kv::{
+        error::Result,
+        option::{Options, ValueCachePolicy},
+    },
+};
+
+/// Weighs cached values by their length in bytes.
+#[derive(Clone, Copy)]
+pub(crate) struct ValueWeigher;
+
+impl Weigher<u64, Bytes> for ValueWeigher {
+    fn weight(&self, _offset: &u64, value: &Bytes) -> u64 {
+        value.len() as u64
+    }
+}
+
+/// Second tier of the S3-FIFO value cache, fed with the values evicted from the main queue.
+///
+/// The eviction listener runs under the exclusive lock of a shard, so it only buffers the values,
+/// which are written to disk by `flush` once the lock is released. Every method of the value cache
+/// flushes the buffer after using the S3-FIFO cache, and lookups in the tier flush it first, so
+/// that a value evicted by another thread is found on disk as soon as it leaves memory.
+pub(crate) struct DiskTier {
+    disk_cache: Arc<DiskCache<u64>>,
+    pending: Mutex<Vec<(u64, Bytes)>>,
+    /// Set once values are buffered, so that flushing an empty buffer skips its lock.
+    dirty: AtomicBool,
+}
+
+impl DiskTier {
+    /// Buffers a value evicted from the main queue until the next flush.
+    fn buffer(&self, offset: u64, value: Bytes) {
+        self.pending.lock().push((offset, value));
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Writes the buffered values to the disk cache.
+    fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Acquire) {
+            return;
+        }
+        let pending = std::mem::take(&mut *self.pending.lock());
+        for (offset, value) in pending {
+            // Failing to write only costs a read from the commit log, and is counted in the
+            // stats of the disk cache.
+            let _ = self.disk_cache.insert(offset, &value);
+        }
+    }
+
+    /// Returns the value at the given offset if it is on disk, writing the buffered values first.
+    fn get(&self, offset: u64) -> Option<Bytes> {
+        self.flush();
+        self.disk_cache.get(&offset)
+    }
+}
+
+/// Cache for values read from the commit log, keyed by their offset in the log.
+/// The eviction policy is chosen through `Options::cache_policy`.
+pub(crate) enum ValueCache {
+    QuickCache(QuickCache<u64, Bytes>),
+    S3Fifo(
+        ShardedCache<u64, Bytes, ValueWeigher>,
+        Option<Arc<DiskTier>>,
+    ),
+    /// One of the policies of the `cache` module without a thread-safe variant of its own.
+    Policy(Mutex<Box<dyn Policy<u64, Bytes> + Send>>),
+}
+
+impl ValueCache {
+    /// Creates a new value cache using the policy and size from the given options.
+    /// The S3-FIFO cache holds up to `max_value_cache_bytes` bytes of values, the other policies
+    /// up to `max_value_cache_size` entries.
+    /// Policies which cannot be empty hold at least one entry.
+    pub(crate) fn new(opts: &Options) -> Self {
+        Self::build(opts, None)
+    }
+
+    /// Creates a new value cache like `new`, which writes the values evicted from the main queue
+    /// of the S3-FIFO policy to the given disk cache. The other policies do not feed it.
+    pub(crate) fn with_disk_cache(opts: &Options, disk_cache: Arc<DiskCache<u64>>) -> Self {
+        Self::build(opts, Some(disk_cache))
+    }
+
+    fn build(opts: &Options, disk_cache: Option<Arc<DiskCache<u64>>>) -> Self {
+        let capacity = opts.max_value_cache_size as usize;
+        let non_zero_capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+
+        match opts.cache_policy {
+            ValueCachePolicy::QuickCache => ValueCache::QuickCache(QuickCache::new(capacity)),
+            ValueCachePolicy::S3Fifo => {
+                // Entries must fit within a single shard, so use no more shards than can each hold
+                // a value of the maximum size.
+                let max_shards = opts.max_value_cache_bytes / opts.max_value_size.max(1);
+                let shards = NonZeroUsize::new(max_shards as usize)
+                    .map_or(NonZeroUsize::MIN, |max_shards| {
+                        min(max_shards, default_shard_count())
+                    });
+                let cache = ShardedCache::with_shards_and_weigher(
+                    opts.max_value_cache_bytes,
+                    shards,
+                    ValueWeigher,
+                );
+                match disk_cache {
+                    Some(disk_cache) => {
+                        let disk_tier = Arc::new(DiskTier {
+                            disk_cache,
+                            pending: Mutex::new(Vec::new()),
+                            dirty: AtomicBool::new(false),
+                        });
+                        let listener = disk_tier.clone();
+                        // Values leaving the small queue were only read once, so only those
+                        // leaving the main queue are worth keeping on disk.
+                        let cache = cache.with_eviction_listener(Arc::new(
+                            move |offset: &u64, value: &Bytes, cause| {
+                                if cause == EvictionCause::MainEvicted {
+                                    listener.buffer(*offset, value.clone());
+                                }
+                            },
+                        ));
+                        ValueCache::S3Fifo(cache, Some(disk_tier))
+                    }
+                    None => ValueCache::S3Fifo(cache, None),
+                }
+            }
+            ValueCachePolicy::Lru => ValueCache::policy(lru::Cache::new(non_zero_capacity)),
+            ValueCachePolicy::Clock => ValueCache::policy(clock::Cache::new(non_zero_capacity)),
+            ValueCachePolicy::Sieve => ValueCache::policy(sieve::Cache::new(non_zero_capacity)),
+            ValueCachePolicy::Arc => ValueCache::policy(arc::Cache::new(non_zero_capacity)),
+        }
+    }
+
+    fn policy<P>(policy: P) -> Self
+    where
+        P: Policy<u64, Bytes> + Send + 'static,
+    {
+        ValueCache::Policy(Mutex::new(Box::new(policy)))
+    }
+
+    /// Returns the cached value at the given offset, if any.
+    pub(crate) fn get(&self, offset: u64) -> Option<Bytes> {
+        match self {
+            ValueCache::QuickCache(cache) => cache.get(&offset),
+            ValueCache::S3Fifo(cache, disk_tier) => {
+                let value = cache.get(&offset);
+                if let Some(disk_tier) = disk_tier {
+                    disk_tier.flush();
+                }
+                value
+            }
+            ValueCache::Policy(cache) => cache.lock().get(&offset).cloned(),
+        }
+    }
+
+    /// Returns the cached value at the given offset, reading it with `read` and caching it on a
+    /// miss. The S3-FIFO policy looks the value up in its disk tier, if any, before calling `read`.
+    /// Errors from `read` are returned without caching anything.
+    pub(crate) fn try_get_or_insert_with<F>(&self, offset: u64, read: F) -> Result<Bytes>
+    where
+        F: FnOnce() -> Result<Bytes>,
+    {
+        match self {
+            ValueCache::QuickCache(cache) => cache.get_or_insert_with(&offset, read),
+            ValueCache::S3Fifo(cache, disk_tier) => {
+                // Concurrent misses on the same offset wait for a single read. A miss costs a read
+                // of the value from the commit log, so the cost of the value is its length, and
+                // hits count the bytes they saved.
+                let value = cache.get_or_load_blocking(offset, || -> Result<(Bytes, u64)> {
+                    let value = match disk_tier.as_ref().and_then(|tier| tier.get(offset)) {
+                        Some(value) => value,
+                        None => read()?,
+                    };
+                    let cost = value.len() as u64;
+                    Ok((value, cost))
+                });
+                if let Some(disk_tier) = disk_tier {
+                    disk_tier.flush();
+                }
+                value
+            }
+            ValueCache::Policy(cache) => {
+                // The lock is not held while reading, so that a miss does not block other readers.
+                if let Some(value) = cache.lock().get(&offset) {
+                    return Ok(value.clone());
+                }
+                let value = read()?;
+                cache.lock().insert(offset, value.clone());
+                Ok(value)
+            }
+        }
+    }
+
+    /// Returns the statistics of the cache, which are not tracked by the quick_cache policy.
+    pub(crate) fn stats(&self) -> Option<CacheStats> {
+        match self {
+            ValueCache::S3Fifo(cache, _) => Some(cache.stats()),
+            ValueCache::Policy(cache) => Some(cache.lock().stats()),
+            ValueCache::QuickCache(_) => None,
+        }
+    }
+
+    /// Saves the cache to a warm-start file tagged with `tag`. Only the S3-FIFO policy supports
+    /// warm starts, the other policies save nothing.
+    pub(crate) fn save_warm_start(&self, path: &Path, tag: u64) -> io::Result<()> {
+        match self {
+            ValueCache::S3Fifo(cache, _) => cache.save_warm_start(path, tag),
+            ValueCache::QuickCache(_) | ValueCache::Policy(_) => Ok(()),
+        }
+    }
+
+    /// Loads a warm-start file saved with the same `tag` into the cache, and returns the number
+    /// of restored values. Only the S3-FIFO policy supports warm starts, the other policies load
+    /// nothing.
+    pub(crate) fn load_warm_start(&self, path: &Path, tag: u64) -> io::Result<usize> {
+        match self {
+            ValueCache::S3Fifo(cache, disk_tier) => {
+                let restored = cache.load_warm_start(path, tag);
+                if let Some(disk_tier) = disk_tier {
+                    disk_tier.flush();
+                }
+                restored
+            }
+            ValueCache::QuickCache(_) | ValueCache::Policy(_) => Ok(0),
+        }
+    }
+
+    /// Starts estimating the miss-ratio curve of the cache, reported through its statistics. Only
+    /// the S3-FIFO policy supports the estimation, the other policies ignore it.
+    pub(crate) fn enable_mrc(&self, max_samples: usize) {
+        if let ValueCache::S3Fifo(cache, _) = self {
+            cache.enable_mrc(max_samples);
+        }
+    }
+
+    /// Stops estimating the miss-ratio curve of the cache.
+    pub(crate) fn disable_mrc(&self) {
+        if let ValueCache::S3Fifo(cache, _) = self {
+            cache.disable_mrc();
+        }
+    }
+
+    /// Caches the value at the given offset.
+    pub(crate) fn insert(&self, offset: u64, value: Bytes) {
+        match self {
+            ValueCache::QuickCache(cache) => cache.insert(offset, value),
+            ValueCache::S3Fifo(cache, disk_tier) => {
+                cache.insert(offset, value);
+                if let Some(disk_tier) = disk_tier {
+                    disk_tier.flush();
+                }
+            }
+            ValueCache::Policy(cache) => {
+                cache.lock().insert(offset, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::error::Error;
+
+    fn options_with_policy(cache_policy: ValueCachePolicy, capacity: u64) -> Options {
+        Options {
+            cache_policy,
+            max_value_cache_size: capacity,
+            max_value_cache_bytes: capacity,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn insert_and_get_with_every_policy() {
+        for policy in [
+            ValueCachePolicy::QuickCache,
+            ValueCachePolicy::S3Fifo,
+            ValueCachePolicy::Lru,
+            ValueCachePolicy::Clock,
+            ValueCachePolicy::Sieve,
+            ValueCachePolicy::Arc,
+        ] {
+            let cache = ValueCache::new(&options_with_policy(policy, 1 << 20));
+            assert!(cache.get(1).is_none());
+
+            cache.insert(1, Bytes::from_static(b"value"));
+            assert_eq!(cache.get(1), Some(Bytes::from_static(b"value")));
+        }
+    }
+
+    #[test]
+    fn s3fifo_capacity_is_in_bytes() {
+        let cache = ValueCache::new(&options_with_policy(ValueCachePolicy::S3Fifo, 1 << 20));
+        for offset in 0..1024 {
+            cache.insert(offset, Bytes::from(vec![0; 4096]));
+        }
+
+        match &cache {
+            ValueCache::S3Fifo(cache, _) => assert!(cache.weight() <= 1 << 20),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn s3fifo_holds_values_of_the_maximum_size() {
+        let mut opts = options_with_policy(ValueCachePolicy::S3Fifo, 1 << 20);
+        opts.max_value_size = 1 << 18;
+        let cache = ValueCache::new(&opts);
+        match &cache {
+            ValueCache::S3Fifo(cache, _) => assert!(cache.shard_count() <= 4),
+            _ => unreachable!(),
+        }
+
+        cache.insert(1, Bytes::from(vec![0; 1 << 18]));
+        assert!(cache.get(1).is_some());
+    }
+
+    #[test]
+    fn stats_are_tracked_by_every_policy_but_quick_cache() {
+        let cache = ValueCache::new(&options_with_policy(ValueCachePolicy::S3Fifo, 1 << 20));
+        cache.insert(1, Bytes::from_static(b"value"));
+        cache.get(1);
+        cache.get(2);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 1);
+
+        let cache = ValueCache::new(&options_with_policy(ValueCachePolicy::Sieve, 10));
+        cache.insert(1, Bytes::from_static(b"value"));
+        cache.get(1);
+        assert_eq!(cache.stats().unwrap().hits, 1);
+
+        let cache = ValueCache::new(&options_with_policy(ValueCachePolicy::QuickCache, 10));
+        assert!(cache.stats().is_none());
+    }
+
+    #[test]
+    fn mrc_is_estimated_by_s3fifo() {
+        let cache = ValueCache::new(&options_with_policy(ValueCachePolicy::S3Fifo, 1 << 20));
+        assert!(cache.stats().unwrap().mrc.is_none());
+
+        cache.enable_mrc(1024);
+        for _ in 0..2 {
+            let value = cache.try_get_or_insert_with(1, || Ok(Bytes::from_static(b"value")));
+            assert!(value.is_ok());
+        }
+        let mrc = cache.stats().unwrap().mrc.unwrap();
+        assert_eq!(mrc.lookups, 2);
+        assert_eq!(mrc.hit_ratio(0.25), Some(0.5));
+
+        cache.disable_mrc();
+        assert!(cache.stats().unwrap().mrc.is_none());
+    }
+
+    #[test]
+    fn main_queue_evictions_go_to_the_disk_cache() {
+        let dir = tempdir::TempDir::new("value_cache").unwrap();
+        let disk_cache = Arc::new(DiskCache::open(&dir.path().join("disk"), 1 << 22).unwrap());
+        let cache = ValueCache::with_disk_cache(
+            &options_with_policy(ValueCachePolicy::S3Fifo, 1 << 20),
+            disk_cache.clone(),
+        );
+
+        // Values read after their insertion are promoted into the main queue, and evicted from it
+        // once it is full. Values which are never read leave from the small queue.
+        let value = Bytes::from(vec![0; 64]);
+        for offset in 0..1 << 15 {
+            cache.insert(offset, value.clone());
+            cache.get(offset);
+        }
+        for offset in 1 << 15..1 << 16 {
+            cache.insert(offset, value.clone());
+        }
+
+        let stats = disk_cache.stats();
+        assert!(stats.insertions > 0);
+        assert_eq!(stats.insertions, cache.stats().unwrap().main_evictions);
+        assert!((0..1 << 15).any(|offset| disk_cache.get(&offset) == Some(value.clone())));
+        assert!((1 << 15..1 << 16).all(|offset| disk_cache.get(&offset).is_none()));
+    }
+
+    #[test]
+    fn values_evicted_to_the_disk_tier_are_found_before_being_flushed() {
+        let dir = tempdir::TempDir::new("value_cache").unwrap();
+        let disk_cache = Arc::new(DiskCache::open(&dir.path().join("disk"), 1 << 22).unwrap());
+        let cache = ValueCache::with_disk_cache(
+            &options_with_policy(ValueCachePolicy::S3Fifo, 1 << 12),
+            disk_cache.clone(),
+        );
+        let ValueCache::S3Fifo(inner, _) = &cache else {
+            unreachable!()
+        };
+
+        // Fill the cache behind the back of the value cache, so that the values evicted from the
+        // main queue stay buffered as if another thread had not flushed them yet.
+        let value = Bytes::from(vec![0; 64]);
+        for offset in 0..256 {
+            inner.insert(offset, value.clone());
+            inner.get(&offset);
+        }
+        assert!(inner.stats().main_evictions > 0);
+        assert_eq!(disk_cache.stats().insertions, 0);
+
+        let evicted = (0..256).find(|offset| !inner.contains_key(offset)).unwrap();
+        let found = cache.try_get_or_insert_with(evicted, || unreachable!());
+        assert_eq!(found.unwrap(), value);
+        assert_eq!(disk_cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn try_get_or_insert_with_every_policy() {
+        for policy in [
+            ValueCachePolicy::QuickCache,
+            ValueCachePolicy::S3Fifo,
+            ValueCachePolicy::Lru,
+            ValueCachePolicy::Clock,
+            ValueCachePolicy::Sieve,
+            ValueCachePolicy::Arc,
+        ] {
+            let cache = ValueCache::new(&options_with_policy(policy, 1 << 20));
+
+            let result = cache.try_get_or_insert_with(1, || Err(Error::CorruptedIndex));
+            assert!(result.is_err());
+            assert!(cache.get(1).is_none());
+
+            let value = cache.try_get_or_insert_with(1, || Ok(Bytes::from_static(b"value")));
+            assert_eq!(value.unwrap(), Bytes::from_static(b"value"));
+            let value = cache.try_get_or_insert_with(1, || unreachable!());
+            assert_eq!(value.unwrap(), Bytes::from_static(b"value"));
+        }
+    }
+
+    #[test]
+    fn zero_capacity_does_not_panic() {
+        for policy in [
+            ValueCachePolicy::QuickCache,
+            ValueCachePolicy::S3Fifo,
+            ValueCachePolicy::Lru,
+            ValueCachePolicy::Clock,
+            ValueCachePolicy::Sieve,
+            ValueCachePolicy::Arc,
+        ] {
+            let cache = ValueCache::new(&options_with_policy(policy, 0));
+            cache.insert(1, Bytes::from_static(b"value"));
+            cache.insert(2, Bytes::from_static(b"value"));
+        }
+    }
+}